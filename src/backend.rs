@@ -0,0 +1,71 @@
+//! What CLI commands need from a REPL, no matter if it's nrepl or prepl
+
+use crate::config::Session;
+use crate::nrepl;
+use crate::nrepl::ops;
+use crate::nrepl::session;
+use crate::nrepl::NreplOp;
+use failure::Error as StdError;
+
+pub use crate::nrepl::ops::EvalResult;
+
+/// Where evaluated code comes from, so errors point at it
+#[derive(Debug, Default, Clone)]
+pub struct Source {
+    pub file: Option<String>,
+    /// Line of `file` the code starts at
+    pub line: Option<i64>,
+    /// Column the code starts at, prepl can't be told it
+    pub column: Option<i64>,
+}
+
+pub trait Backend {
+    /// Evaluates `code` in `ns`, or in the default namespace of the REPL
+    fn eval(&self, code: &str, ns: Option<&str>, source: &Source) -> Result<EvalResult, StdError>;
+
+    /// Namespace declared in clojure `file`
+    fn ns_name(&self, file: &str) -> Result<Option<String>, StdError>;
+
+    /// Position and documentation of `symbol` as it's seen from `ns`
+    fn info(&self, ns: &str, symbol: &str) -> Result<Option<ops::InfoResponseType>, StdError>;
+}
+
+/// nrepl's `eval` op for `code`
+pub fn eval_op(session: Session, code: &str, ns: Option<&str>, source: &Source) -> ops::Eval {
+    let mut op = ops::Eval::new(session, code.to_string());
+
+    if let Some(ns) = ns {
+        op.set_ns(ns.to_string());
+    }
+    if let Some(file) = &source.file {
+        op.set_file(file.clone());
+    }
+    if let Some(line) = source.line {
+        op.set_line(line);
+    }
+    if let Some(column) = source.column {
+        op.set_column(column);
+    }
+
+    op
+}
+
+impl Backend for nrepl::NreplStream {
+    fn eval(&self, code: &str, ns: Option<&str>, source: &Source) -> Result<EvalResult, StdError> {
+        let session = session::get_existing_session_id(self)?;
+
+        eval_op(session, code, ns, source).send(self)
+    }
+
+    fn ns_name(&self, file: &str) -> Result<Option<String>, StdError> {
+        let session = session::get_existing_session_id(self)?;
+
+        ops::GetNsName::new(file.to_string(), session).send(self)
+    }
+
+    fn info(&self, ns: &str, symbol: &str) -> Result<Option<ops::InfoResponseType>, StdError> {
+        let session = session::get_existing_session_id(self)?;
+
+        ops::Info::new(session, ns.to_string(), symbol.to_string()).send(self)
+    }
+}
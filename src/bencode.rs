@@ -1,6 +1,20 @@
+//! Bencode values and their decoding.
+//!
+//! Messages are decoded incrementally: `Decoder` keeps whatever part of a message has arrived
+//! and only finds where the message ends until it's complete, then values are built in a single
+//! pass. `ValueRef` points into the decoder's buffer, nrepl connection reads message `id` from
+//! it and copies the rest only when some op waits for the message.
+
+pub mod de;
+
+use base64::Engine;
 use failure::Fail;
+use serde::ser::{Serialize, SerializeMap, Serializer};
 use serde_bencode::value::Value;
 use serde_json::value::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, ErrorKind};
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -8,6 +22,20 @@ pub enum Error {
     InvalidType { bc: String },
     #[fail(display = "failed to parse utf8: {}", utf8err)]
     Utf8Error { utf8err: std::string::FromUtf8Error },
+    #[fail(display = "bencode io error: {}", ioerr)]
+    IOError { ioerr: std::io::Error },
+    #[fail(display = "stream ended in the middle of bencode message")]
+    UnexpectedEof,
+    #[fail(display = "bad bencode at byte {}: {}", pos, msg)]
+    Syntax { pos: usize, msg: &'static str },
+    #[fail(display = "can't be sent as bencode: {}", json)]
+    NotBencodable { json: String },
+}
+
+impl From<std::io::Error> for Error {
+    fn from(ioerr: std::io::Error) -> Self {
+        Self::IOError { ioerr }
+    }
 }
 
 impl From<std::string::FromUtf8Error> for Error {
@@ -29,7 +57,7 @@ pub fn try_into_string(val: Value) -> Result<String, Error> {
 pub fn try_into_str_vec(val: Value) -> Result<Vec<String>, Error> {
     if let Value::List(vals) = val {
         vals.into_iter()
-            .map(|v| try_into_string(v))
+            .map(try_into_string)
             .collect::<Result<Vec<String>, Error>>()
     } else {
         Err(Error::InvalidType {
@@ -48,29 +76,520 @@ pub fn try_into_int(val: Value) -> Result<i64, Error> {
     }
 }
 
-#[derive(Debug)]
-pub enum JsonError {}
+/// Value with byte strings told apart: text when they are valid UTF-8, raw bytes otherwise,
+/// like sideloaded class files or `out` of a program which doesn't print UTF-8
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypedValue {
+    Text(String),
+    Bytes(Vec<u8>),
+    Int(i64),
+    List(Vec<TypedValue>),
+    Dict(BTreeMap<String, TypedValue>),
+}
+
+/// Dict keys are expected to be text
+impl TryFrom<Value> for TypedValue {
+    type Error = std::string::FromUtf8Error;
+
+    fn try_from(val: Value) -> Result<Self, Self::Error> {
+        Ok(match val {
+            Value::Bytes(bs) => match String::from_utf8(bs) {
+                Ok(s) => TypedValue::Text(s),
+                Err(e) => TypedValue::Bytes(e.into_bytes()),
+            },
+            Value::Int(n) => TypedValue::Int(n),
+            Value::List(vals) => TypedValue::List(
+                vals.into_iter()
+                    .map(TypedValue::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Dict(map) => TypedValue::Dict(
+                map.into_iter()
+                    .map(|(k, v)| Ok((String::from_utf8(k)?, TypedValue::try_from(v)?)))
+                    .collect::<Result<_, Self::Error>>()?,
+            ),
+        })
+    }
+}
+
+/// Raw bytes become `{"base64": "..."}`
+impl Serialize for TypedValue {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            TypedValue::Text(text) => s.serialize_str(text),
+            TypedValue::Bytes(bs) => {
+                let mut map = s.serialize_map(Some(1))?;
+                map.serialize_entry(
+                    "base64",
+                    &base64::engine::general_purpose::STANDARD.encode(bs),
+                )?;
+                map.end()
+            }
+            TypedValue::Int(n) => s.serialize_i64(*n),
+            TypedValue::List(vals) => vals.serialize(s),
+            TypedValue::Dict(map) => map.serialize(s),
+        }
+    }
+}
+
+/// Reverse of `to_json_value`. Strings, integers, arrays and objects are converted as they
+/// are, `true` becomes `"true"`. `false` and `null` mean there's no value, so they are left out
+/// of objects, there's no way to send them otherwise.
+pub fn from_json_value(json: JsonValue) -> Result<Option<Value>, Error> {
+    let not_bencodable = |json: &JsonValue| Error::NotBencodable {
+        json: json.to_string(),
+    };
+
+    Ok(Some(match json {
+        JsonValue::Null | JsonValue::Bool(false) => return Ok(None),
+        JsonValue::Bool(true) => Value::Bytes(b"true".to_vec()),
+        JsonValue::String(s) => Value::Bytes(s.into_bytes()),
+        JsonValue::Number(ref n) => Value::Int(n.as_i64().ok_or_else(|| not_bencodable(&json))?),
+        JsonValue::Array(vals) => Value::List(
+            vals.into_iter()
+                .map(|val| from_json_value(val.clone())?.ok_or_else(|| not_bencodable(&val)))
+                .collect::<Result<_, _>>()?,
+        ),
+        JsonValue::Object(map) => {
+            let mut dict = std::collections::HashMap::new();
+
+            for (k, v) in map {
+                if let Some(v) = from_json_value(v)? {
+                    dict.insert(k.into_bytes(), v);
+                }
+            }
+            Value::Dict(dict)
+        }
+    }))
+}
+
+pub fn to_json_value(val: Value) -> Result<JsonValue, Error> {
+    let typed = TypedValue::try_from(val)?;
+
+    // Serializing to `JsonValue` doesn't fail on text keys
+    Ok(serde_json::to_value(typed).unwrap())
+}
+
+/// Value which borrows its strings from the buffer it was decoded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueRef<'a> {
+    Bytes(&'a [u8]),
+    Int(i64),
+    List(Vec<ValueRef<'a>>),
+    /// Entries in the order they were sent
+    Dict(Vec<(&'a [u8], ValueRef<'a>)>),
+}
+
+impl<'a> ValueRef<'a> {
+    /// Entry of a dict
+    pub fn get(&self, key: &str) -> Option<&ValueRef<'a>> {
+        match self {
+            ValueRef::Dict(entries) => entries
+                .iter()
+                .find(|(k, _)| *k == key.as_bytes())
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            ValueRef::Bytes(bs) => Value::Bytes(bs.to_vec()),
+            ValueRef::Int(n) => Value::Int(*n),
+            ValueRef::List(vals) => Value::List(vals.iter().map(ValueRef::to_value).collect()),
+            ValueRef::Dict(entries) => Value::Dict(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_vec(), v.to_value()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Finds where the message ends, remembering how far it got between calls
+#[derive(Debug, Default)]
+struct Scanner {
+    /// Start of the first token which wasn't complete yet
+    pos: usize,
+    /// Lists and dicts which are not closed yet
+    depth: usize,
+}
+
+/// Longest length prefix of a string, `usize::MAX` has 20 digits
+const MAX_LEN_DIGITS: usize = 20;
+/// Values are parsed recursively, deeper messages would overflow the reader's stack
+const MAX_DEPTH: usize = 512;
+
+impl Scanner {
+    /// Length of the message at the start of `buf`, `None` until it's complete. `buf` is
+    /// expected to only grow between calls.
+    fn scan(&mut self, buf: &[u8]) -> Result<Option<usize>, Error> {
+        loop {
+            let rest = &buf[self.pos..];
+            let syntax = |msg| Error::Syntax { pos: self.pos, msg };
+
+            let len = match rest.first() {
+                None => return Ok(None),
+                Some(b'i') => match rest.iter().position(|&b| b == b'e') {
+                    Some(end) => end + 1,
+                    None => return Ok(None),
+                },
+                Some(b'l') | Some(b'd') => {
+                    if self.depth == MAX_DEPTH {
+                        return Err(syntax("nesting is too deep"));
+                    }
+                    self.depth += 1;
+                    1
+                }
+                Some(b'e') if self.depth > 0 => {
+                    self.depth -= 1;
+                    1
+                }
+                Some(b'0'..=b'9') => {
+                    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+
+                    if digits > MAX_LEN_DIGITS {
+                        return Err(syntax("string is too long"));
+                    }
+                    match rest.get(digits) {
+                        None => return Ok(None),
+                        Some(b':') => (),
+                        Some(_) => return Err(syntax("expected `:` after string length")),
+                    }
+
+                    let n: usize = std::str::from_utf8(&rest[..digits])
+                        .ok()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| syntax("string is too long"))?;
+
+                    match (digits + 1).checked_add(n) {
+                        Some(len) if len <= rest.len() => len,
+                        Some(_) => return Ok(None),
+                        None => return Err(syntax("string is too long")),
+                    }
+                }
+                Some(_) => return Err(syntax("unexpected byte")),
+            };
+
+            self.pos += len;
+
+            if self.depth == 0 {
+                let end = self.pos;
+                *self = Scanner::default();
+                return Ok(Some(end));
+            }
+        }
+    }
+}
+
+/// Builds value of a message which `Scanner` has found complete
+fn parse<'a>(buf: &'a [u8], pos: &mut usize) -> Result<ValueRef<'a>, Error> {
+    let syntax = |pos, msg| Error::Syntax { pos, msg };
+    let start = *pos;
+
+    match buf[start] {
+        b'i' => {
+            let end = start + buf[start..].iter().position(|&b| b == b'e').unwrap();
+            let n = std::str::from_utf8(&buf[start + 1..end])
+                .ok()
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| syntax(start, "bad integer"))?;
+
+            *pos = end + 1;
+            Ok(ValueRef::Int(n))
+        }
+        b'l' => {
+            let mut vals = vec![];
+            *pos += 1;
+
+            while buf[*pos] != b'e' {
+                vals.push(parse(buf, pos)?);
+            }
+            *pos += 1;
+            Ok(ValueRef::List(vals))
+        }
+        b'd' => {
+            let mut entries = vec![];
+            *pos += 1;
+
+            while buf[*pos] != b'e' {
+                let key_pos = *pos;
+                let key = match parse(buf, pos)? {
+                    ValueRef::Bytes(key) => key,
+                    _ => return Err(syntax(key_pos, "dict key is not a string")),
+                };
+                if buf[*pos] == b'e' {
+                    return Err(syntax(*pos, "dict key without value"));
+                }
+                entries.push((key, parse(buf, pos)?));
+            }
+            *pos += 1;
+            Ok(ValueRef::Dict(entries))
+        }
+        _ => {
+            let colon = start + buf[start..].iter().position(|&b| b == b':').unwrap();
+            // Scanner has checked the length
+            let n: usize = std::str::from_utf8(&buf[start..colon])
+                .unwrap()
+                .parse()
+                .unwrap();
+
+            *pos = colon + 1 + n;
+            Ok(ValueRef::Bytes(&buf[colon + 1..*pos]))
+        }
+    }
+}
+
+fn parse_message(buf: &[u8]) -> Result<ValueRef<'_>, Error> {
+    parse(buf, &mut 0)
+}
+
+/// Decodes messages which arrive in arbitrary pieces
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    scanner: Scanner,
+    /// Start of the first message which wasn't returned yet, bytes before it are dropped when
+    /// more are fed
+    start: usize,
+    /// There are bytes which aren't bencode, nothing after them can be decoded
+    broken: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Next complete message, it's valid until the next call. Message which is complete but
+    /// malformed is skipped, so decoding can go on after its error. Other errors mean the rest
+    /// of the stream can't be decoded.
+    pub fn next_ref(&mut self) -> Result<Option<ValueRef<'_>>, Error> {
+        let start = self.start;
+
+        match self.scanner.scan(&self.buf[start..]) {
+            Ok(Some(len)) => {
+                self.start += len;
+                Ok(Some(parse_message(&self.buf[start..self.start])?))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.broken = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// Last error of `next_ref` can't be skipped
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    pub fn next_value(&mut self) -> Result<Option<Value>, Error> {
+        Ok(self.next_ref()?.map(|val| val.to_value()))
+    }
+
+    /// There's no part of a message waiting for the rest of it
+    pub fn is_empty(&self) -> bool {
+        self.buf.len() == self.start
+    }
+}
+
+/// Reads single message, `None` when `r` has ended before it. Only the bytes of the message are
+/// consumed from `r`, whatever comes next stays in its buffer.
+pub fn read_value<R: BufRead>(r: &mut R) -> Result<Option<Value>, Error> {
+    let mut scanner = Scanner::default();
+    let mut msg: Vec<u8> = vec![];
+
+    loop {
+        let chunk = match r.fill_buf() {
+            Ok(chunk) => chunk,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        if chunk.is_empty() {
+            if msg.is_empty() {
+                return Ok(None);
+            }
+            return Err(Error::UnexpectedEof);
+        }
+
+        // Usually the whole message is in the buffer already, so it's not copied
+        if msg.is_empty() {
+            if let Some(len) = scanner.scan(chunk)? {
+                let val = parse_message(&chunk[..len])?.to_value();
+                r.consume(len);
+                return Ok(Some(val));
+            }
+        }
+
+        let old_len = msg.len();
+        msg.extend_from_slice(chunk);
+
+        match scanner.scan(&msg)? {
+            Some(len) => {
+                r.consume(len - old_len);
+                return Ok(Some(parse_message(&msg[..len])?.to_value()));
+            }
+            None => r.consume(msg.len() - old_len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    #[test]
+    fn binary_strings_are_kept_as_bytes_test() {
+        let val = Value::Dict(
+            vec![
+                (b"out".to_vec(), Value::Bytes(b"hi".to_vec())),
+                (
+                    b"class".to_vec(),
+                    Value::Bytes(vec![0xca, 0xfe, 0xba, 0xbe]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(
+            to_json_value(val).unwrap(),
+            serde_json::json!({"out": "hi", "class": {"base64": "yv66vg=="}})
+        );
+
+        let bad_key = Value::Dict(vec![(vec![0xff], Value::Int(1))].into_iter().collect());
+        assert!(TypedValue::try_from(bad_key).is_err());
+    }
+
+    #[test]
+    fn json_roundtrip_test() {
+        let json = serde_json::json!({
+            "id": 1584962410123456789i64,
+            "exclude": ["clojure.core", "user"],
+            "nrepl.middleware.print/options": {"right-margin": 80, "length": null},
+            "verbose?": true,
+            "privates?": false,
+        });
+
+        let val = from_json_value(json).unwrap().unwrap();
+        assert_eq!(
+            to_json_value(val).unwrap(),
+            serde_json::json!({
+                "id": 1584962410123456789i64,
+                "exclude": ["clojure.core", "user"],
+                "nrepl.middleware.print/options": {"right-margin": 80},
+                "verbose?": "true",
+            })
+        );
+
+        for bad in [serde_json::json!(1.5), serde_json::json!([null])].iter() {
+            assert!(from_json_value(bad.clone()).is_err());
+        }
+    }
+
+    #[test]
+    fn decoder_handles_partial_messages_test() {
+        let msgs = b"d3:out5:hello6:statusl4:doneee" as &[u8];
+        let mut decoder = Decoder::new();
 
-pub fn to_json_value(val: Value) -> Result<JsonValue, JsonError> {
-    match val {
-        Value::Bytes(bs) => Ok(serde_json::Value::String(String::from_utf8(bs).unwrap())),
-        Value::List(items) => Ok(JsonValue::Array(
-            items
-                .into_iter()
-                .map(|i| Ok(to_json_value(i)?))
-                .collect::<Result<Vec<JsonValue>, JsonError>>()?,
-        )),
-        Value::Dict(hm) => {
-            let m = hm
-                .into_iter()
-                .map(|(k, v)| Ok((String::from_utf8(k).unwrap(), to_json_value(v)?)))
-                .collect::<Result<serde_json::Map<String, JsonValue>, JsonError>>()?;
+        // Byte by byte, then two messages at once
+        for b in msgs.iter() {
+            assert_eq!(decoder.next_ref().unwrap(), None);
+            decoder.feed(&[*b]);
+        }
+        decoder.feed(b"i-42eli1e0:e");
+
+        let first = decoder.next_ref().unwrap().unwrap();
+        assert_eq!(first.get("out"), Some(&ValueRef::Bytes(b"hello")));
+        assert_eq!(
+            first.get("status"),
+            Some(&ValueRef::List(vec![ValueRef::Bytes(b"done")]))
+        );
+
+        assert_eq!(decoder.next_value().unwrap(), Some(Value::Int(-42)));
+        assert_eq!(
+            decoder.next_value().unwrap(),
+            Some(Value::List(vec![Value::Int(1), Value::Bytes(vec![])]))
+        );
+        assert_eq!(decoder.next_ref().unwrap(), None);
+        assert!(decoder.is_empty());
+
+        for bad in [&b"x"[..], b"di1ei2ee", b"d3:key", b"5x", b"ie"].iter() {
+            let mut decoder = Decoder::new();
+            decoder.feed(bad);
+            match decoder.next_ref() {
+                Err(Error::Syntax { .. }) if *bad != b"d3:key" => (),
+                Ok(None) if *bad == b"d3:key" => (),
+                res => panic!("unexpected result for {:?}: {:?}", bad, res),
+            }
+        }
+
+        // Malformed message is skipped, bytes which aren't bencode stop decoding
+        let mut decoder = Decoder::new();
+        decoder.feed(b"di1ei2eei7e");
+        assert!(decoder.next_ref().is_err());
+        assert!(!decoder.is_broken());
+        assert_eq!(decoder.next_value().unwrap(), Some(Value::Int(7)));
+
+        decoder.feed(b"xi8e");
+        assert!(decoder.next_ref().is_err());
+        assert!(decoder.is_broken());
+    }
+
+    #[test]
+    fn deep_nesting_is_refused_test() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
 
-            Ok(JsonValue::Object(m))
+        let mut decoder = Decoder::new();
+        decoder.feed(&nested(MAX_DEPTH));
+        assert!(decoder.next_ref().unwrap().is_some());
+
+        // Refused before the rest of it arrives
+        let mut decoder = Decoder::new();
+        decoder.feed(&vec![b'l'; 200_000]);
+        match decoder.next_ref() {
+            Err(Error::Syntax { pos, msg }) => {
+                assert_eq!((pos, msg), (MAX_DEPTH, "nesting is too deep"))
+            }
+            res => panic!("expected syntax error, got: {:?}", res),
         }
+    }
 
-        Value::Int(i) => Ok(JsonValue::Number(
-            serde_json::Number::from_f64(i as f64).unwrap(),
-        )),
+    #[test]
+    fn read_value_leaves_next_message_test() {
+        let msgs = b"d2:id1:1e5:hello" as &[u8];
+        // Messages are split between reads of the tiny buffer
+        let mut r = BufReader::with_capacity(3, msgs);
+
+        let first = read_value(&mut r).unwrap().unwrap();
+        assert_eq!(
+            first,
+            Value::Dict(
+                vec![(b"id".to_vec(), Value::Bytes(b"1".to_vec()))]
+                    .into_iter()
+                    .collect()
+            )
+        );
+        assert_eq!(
+            read_value(&mut r).unwrap(),
+            Some(Value::Bytes(b"hello".to_vec()))
+        );
+        assert_eq!(read_value(&mut r).unwrap(), None);
+
+        match read_value(&mut BufReader::new(&b"d2:id"[..])) {
+            Err(Error::UnexpectedEof) => (),
+            res => panic!("expected unexpected eof, got: {:?}", res),
+        }
     }
 }
@@ -0,0 +1,292 @@
+//! Reading bencode values into `#[derive(Deserialize)]` structs.
+//!
+//! Byte strings are read as strings when they're UTF-8 and as bytes otherwise. Missing fields
+//! are `None` for `Option`s. Booleans are accepted as `"true"`/`"false"` strings or as
+//! integers, that's how nrepl middleware sends them. Errors tell where the bad field is, like
+//! "`spec[1]`: invalid type: integer `1`, expected a string".
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Unexpected,
+    Visitor,
+};
+use serde::forward_to_deserialize_any;
+use serde_bencode::value::Value;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct Error {
+    /// Like `ops.eval` or `spec[1]`, empty for the value itself
+    path: String,
+    msg: String,
+}
+
+impl Error {
+    /// Error is moved into the value of `key`
+    fn in_key(mut self, key: &[u8]) -> Error {
+        let key = String::from_utf8_lossy(key);
+
+        self.path = match self.path.chars().next() {
+            None => key.into_owned(),
+            Some('[') => format!("{}{}", key, self.path),
+            Some(_) => format!("{}.{}", key, self.path),
+        };
+        self
+    }
+
+    fn in_index(mut self, idx: usize) -> Error {
+        self.path = match self.path.chars().next() {
+            None | Some('[') => format!("[{}]{}", idx, self.path),
+            Some(_) => format!("[{}].{}", idx, self.path),
+        };
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.msg)
+        } else {
+            write!(f, "`{}`: {}", self.path, self.msg)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error {
+            path: String::new(),
+            msg: msg.to_string(),
+        }
+    }
+}
+
+pub fn from_value<T: DeserializeOwned>(val: Value) -> Result<T, Error> {
+    T::deserialize(Deserializer(val))
+}
+
+struct Deserializer(Value);
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Bytes(bs) => match String::from_utf8(bs) {
+                Ok(s) => visitor.visit_string(s),
+                Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+            },
+            Value::Int(n) => visitor.visit_i64(n),
+            Value::List(vals) => visitor.visit_seq(Seq {
+                vals: vals.into_iter(),
+                idx: 0,
+            }),
+            Value::Dict(map) => visitor.visit_map(Map {
+                entries: map.into_iter(),
+                next: None,
+            }),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.0 {
+            Value::Bytes(bs) if bs == b"true" => visitor.visit_bool(true),
+            Value::Bytes(bs) if bs == b"false" => visitor.visit_bool(false),
+            Value::Int(n) => visitor.visit_bool(*n != 0),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Bytes(bs) => match String::from_utf8(bs) {
+                Ok(s) => visitor.visit_string(s),
+                Err(e) => Err(de::Error::invalid_value(
+                    Unexpected::Bytes(e.as_bytes()),
+                    &"UTF-8 string",
+                )),
+            },
+            val => Deserializer(val).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Bytes(bs) => visitor.visit_byte_buf(bs),
+            val => Deserializer(val).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    /// Absent fields are `None`, anything which is there is `Some`
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    /// Only unit variants, given as strings like `"function"`
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Bytes(bs) => match String::from_utf8(bs) {
+                Ok(s) => visitor.visit_enum(s.into_deserializer()),
+                Err(e) => Err(de::Error::invalid_value(
+                    Unexpected::Bytes(e.as_bytes()),
+                    &"UTF-8 string",
+                )),
+            },
+            val => Deserializer(val).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char
+        unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+struct Seq {
+    vals: std::vec::IntoIter<Value>,
+    idx: usize,
+}
+
+impl<'de> SeqAccess<'de> for Seq {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        let val = match self.vals.next() {
+            Some(val) => val,
+            None => return Ok(None),
+        };
+        let idx = self.idx;
+        self.idx += 1;
+
+        seed.deserialize(Deserializer(val))
+            .map(Some)
+            .map_err(|e| e.in_index(idx))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.vals.len())
+    }
+}
+
+struct Map {
+    entries: std::collections::hash_map::IntoIter<Vec<u8>, Value>,
+    /// Key and value of the entry being read
+    next: Option<(Vec<u8>, Value)>,
+}
+
+impl<'de> MapAccess<'de> for Map {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let (key, val) = match self.entries.next() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let res = seed
+            .deserialize(Deserializer(Value::Bytes(key.clone())))
+            .map_err(|e| e.in_key(&key));
+        self.next = Some((key, val));
+
+        res.map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (key, val) = self
+            .next
+            .take()
+            .ok_or_else(|| de::Error::custom("value is read before its key"))?;
+
+        seed.deserialize(Deserializer(val))
+            .map_err(|e| e.in_key(&key))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    struct Candidate {
+        candidate: String,
+        #[serde(rename = "type")]
+        kind: Option<String>,
+        arglists_str: Option<String>,
+        #[serde(default)]
+        private: bool,
+        line: Option<i64>,
+        spec: Option<Vec<String>>,
+    }
+
+    fn msg(bs: &[u8]) -> Value {
+        serde_bencode::from_bytes(bs).unwrap()
+    }
+
+    #[test]
+    fn struct_fields_are_read_test() {
+        let c: Candidate = from_value(msg(
+            b"d9:candidate3:map4:type8:function12:arglists-str6:[f xs]7:private4:true\
+              4:linei12ee",
+        ))
+        .unwrap();
+
+        assert_eq!(
+            c,
+            Candidate {
+                candidate: "map".to_string(),
+                kind: Some("function".to_string()),
+                arglists_str: Some("[f xs]".to_string()),
+                private: true,
+                line: Some(12),
+                spec: None,
+            }
+        );
+
+        let err = from_value::<Candidate>(msg(b"d9:candidate3:map4:specl1:ai1eee")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`spec[1]`: invalid type: integer `1`, expected a string"
+        );
+
+        let err = from_value::<Vec<Candidate>>(msg(b"ld4:type5:macroee")).unwrap_err();
+        assert_eq!(err.to_string(), "`[0]`: missing field `candidate`");
+    }
+}
@@ -1,9 +1,23 @@
+//! Helper functions for commandline
+
+pub mod apropos;
+pub mod complete;
+pub mod daemon;
+pub mod decode;
+pub mod doc;
+pub mod eldoc;
+pub mod eval;
 pub mod find_def;
+pub mod interrupt;
+pub mod load;
 pub mod op;
-pub mod doc;
 pub mod read_jar;
+pub mod replay;
 
-///! Helper functions for commandline
+use crate::nrepl;
+use signal_hook::consts::SIGINT;
+use signal_hook::iterator::Signals;
+use std::thread;
 
 pub fn die_err(msg: &str) -> ! {
     eprintln!("{}", msg);
@@ -16,6 +30,14 @@ pub fn print_parseable(data: &Vec<(&str, String)>) {
     }
 }
 
+/// First line of docstring with something in it, for one-line hints
+pub fn first_doc_line(doc: &str) -> &str {
+    doc.lines()
+        .map(|l| l.trim())
+        .find(|l| !l.is_empty())
+        .unwrap_or_default()
+}
+
 pub fn die_if_err<T, E: std::fmt::Display>(res: Result<T, E>) -> T {
     match res {
         Ok(t) => t,
@@ -24,3 +46,27 @@ pub fn die_if_err<T, E: std::fmt::Display>(res: Result<T, E>) -> T {
         }
     }
 }
+
+/// First Ctrl-C asks nrepl to interrupt `pending` op, the next one exits right away
+pub fn interrupt_on_ctrl_c(pending: &nrepl::PendingOp) {
+    let interrupter = pending.interrupter();
+    let mut signals = die_if_err(Signals::new([SIGINT]));
+
+    thread::spawn(move || {
+        let mut interrupting = false;
+
+        for _ in signals.forever() {
+            match &interrupter {
+                Some(interrupter) if !interrupting => {
+                    interrupting = true;
+                    eprintln!("Interrupting, press Ctrl-C again to exit");
+
+                    if let Err(e) = interrupter.interrupt() {
+                        die_err(&format!("ERROR: {}", e));
+                    }
+                }
+                _ => std::process::exit(130),
+            }
+        }
+    });
+}
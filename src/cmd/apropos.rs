@@ -0,0 +1,53 @@
+use crate::cmd;
+use crate::nrepl;
+use crate::nrepl::ops;
+use crate::nrepl::session;
+use crate::nrepl::NreplOp;
+use clap::{clap_app, App, ArgMatches};
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    clap_app!(apropos =>
+        (about: "Finds vars matching QUERY in loaded namespaces, a line for each: NAME TYPE DOC, separated with tabs")
+        (@arg QUERY: +required "Regex, or just a part of the name")
+        (@arg NS: +takes_value -n --ns "Searches only in NS")
+        (@arg DOCS: -d --docs "Searches in docstrings as well")
+        (@arg PRIVATES: --privates "Includes private vars")
+        (@arg CASE_SENSITIVE: --("case-sensitive") "Case-sensitive search")
+        (@arg JSON: --json "Prints JSON array of objects with name, type and doc")
+    )
+}
+
+pub fn run(matches: &ArgMatches, nrepl_stream: &nrepl::NreplStream) {
+    let session = cmd::die_if_err(session::get_existing_session_id(nrepl_stream));
+    let mut op = ops::Apropos::new(session, matches.value_of("QUERY").unwrap().to_string());
+
+    if let Some(ns) = matches.value_of("NS") {
+        op.set_ns(ns.to_string());
+    }
+    op.set_docs(matches.is_present("DOCS"));
+    op.set_privates(matches.is_present("PRIVATES"));
+    op.set_case_sensitive(matches.is_present("CASE_SENSITIVE"));
+
+    let mut hits = cmd::die_if_err(op.send(nrepl_stream));
+
+    for hit in hits.iter_mut() {
+        hit.doc = hit
+            .doc
+            .as_deref()
+            .map(|doc| cmd::first_doc_line(doc).to_string());
+    }
+
+    if matches.is_present("JSON") {
+        println!("{}", cmd::die_if_err(serde_json::to_string(&hits)));
+        return;
+    }
+
+    for hit in hits {
+        println!(
+            "{}\t{}\t{}",
+            hit.name,
+            hit.kind.unwrap_or_default(),
+            hit.doc.unwrap_or_default()
+        );
+    }
+}
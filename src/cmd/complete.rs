@@ -0,0 +1,43 @@
+use crate::cmd;
+use crate::nrepl;
+use crate::nrepl::ops;
+use crate::nrepl::session;
+use crate::nrepl::NreplOp;
+use clap::{clap_app, App, ArgMatches};
+use std::path::Path;
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    clap_app!(complete =>
+        (about: "Shows completions of PREFIX, a line for each: CANDIDATE TYPE NS ARGLISTS, separated with tabs")
+        (@arg FILE: +required "FILE with NS the completion is done in")
+        (@arg PREFIX: +required "PREFIX")
+        (@arg JSON: --json "Prints JSON array of objects with candidate, type, ns and arglists")
+    )
+}
+
+pub fn run(matches: &ArgMatches, nrepl_stream: &nrepl::NreplStream) {
+    let file = cmd::die_if_err(Path::new(matches.value_of("FILE").unwrap()).canonicalize());
+    let prefix = matches.value_of("PREFIX").unwrap().to_string();
+    let session = cmd::die_if_err(session::get_existing_session_id(nrepl_stream));
+
+    let ns = cmd::die_if_err(
+        ops::GetNsName::new(file.to_string_lossy().into_owned(), session.clone())
+            .send(nrepl_stream),
+    );
+    let candidates = cmd::die_if_err(ops::Complete::new(session, ns, prefix).send(nrepl_stream));
+
+    if matches.is_present("JSON") {
+        println!("{}", cmd::die_if_err(serde_json::to_string(&candidates)));
+        return;
+    }
+
+    for c in candidates {
+        println!(
+            "{}\t{}\t{}\t{}",
+            c.candidate,
+            c.kind.unwrap_or_default(),
+            c.ns.unwrap_or_default(),
+            c.arglists.join(" ")
+        );
+    }
+}
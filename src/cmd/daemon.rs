@@ -0,0 +1,19 @@
+use crate::cmd;
+use crate::config;
+use crate::daemon;
+use clap::{clap_app, App, ArgMatches};
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    clap_app!(daemon =>
+        (about: "Keeps nrepl connections and sessions open, other commands use it when it's running")
+    )
+}
+
+pub fn run(_matches: &ArgMatches) {
+    cmd::die_if_err(config::ensure_migrations());
+
+    let path = daemon::socket_path();
+    eprintln!("Listening on {}", path.display());
+
+    cmd::die_if_err(daemon::serve(&path));
+}
@@ -0,0 +1,69 @@
+use crate::bencode;
+use crate::cmd;
+use crate::edn;
+use crate::nrepl::trace;
+use clap::{clap_app, App, ArgMatches};
+use serde_bencode::value::Value as BencodeValue;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    clap_app!(decode =>
+        (about: "Shows bencode messages from `--trace` log or raw capture of nrepl traffic")
+        (@arg FILE: +takes_value "File with bencode messages, stdin by default")
+        (@arg EDN: --edn "Prints messages as EDN instead of JSON")
+    )
+}
+
+/// `HH:MM:SS.mmm` in UTC
+fn time_of_day(millis: i64) -> String {
+    let ms = millis.rem_euclid(86_400_000);
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn show(msg: BencodeValue, as_edn: bool) -> String {
+    if as_edn {
+        edn::to_string(&msg)
+    } else {
+        let json = cmd::die_if_err(bencode::to_json_value(msg));
+        serde_json::to_string_pretty(&json).unwrap()
+    }
+}
+
+pub fn run(matches: &ArgMatches) {
+    let as_edn = matches.is_present("EDN");
+    let mut r: Box<dyn BufRead> = match matches.value_of("FILE") {
+        Some(path) => Box::new(BufReader::new(cmd::die_if_err(File::open(path)))),
+        None => Box::new(BufReader::new(std::io::stdin())),
+    };
+    let mut n = 0;
+
+    while let Some(val) = cmd::die_if_err(bencode::read_value(&mut r)) {
+        n += 1;
+
+        match trace::Entry::from_value(val) {
+            Ok(entry) => {
+                let arrow = if entry.dir == trace::SENT { ">>" } else { "<<" };
+                println!(
+                    ";; {} #{} {} UTC {}",
+                    arrow,
+                    n,
+                    time_of_day(entry.time),
+                    entry.peer
+                );
+                println!("{}", show(entry.msg, as_edn));
+            }
+            Err(msg) => {
+                println!(";; #{}", n);
+                println!("{}", show(msg, as_edn));
+            }
+        }
+    }
+}
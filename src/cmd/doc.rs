@@ -1,8 +1,5 @@
+use crate::backend::Backend;
 use crate::cmd;
-use crate::nrepl;
-use crate::nrepl::ops;
-use crate::nrepl::session;
-use crate::nrepl::NreplOp;
 use clap::{clap_app, App, ArgMatches};
 
 struct Opts {
@@ -27,12 +24,10 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
     )
 }
 
-pub fn run(matches: &ArgMatches, nrepl_stream: &nrepl::NreplStream) {
+pub fn run(matches: &ArgMatches, backend: &dyn Backend) {
     let opts = Opts::parse(matches);
-    let session = cmd::die_if_err(session::get_existing_session_id(nrepl_stream));
-    let ns = cmd::die_if_err(ops::GetNsName::new(opts.file, session.clone()).send(nrepl_stream));
-    let op = ops::Info::new(session, ns.unwrap(), opts.symbol);
-    let res = cmd::die_if_err(op.send(nrepl_stream));
+    let ns = cmd::die_if_err(backend.ns_name(&opts.file));
+    let res = cmd::die_if_err(backend.info(&ns.unwrap(), &opts.symbol));
 
     if let Some(res) = res {
         println!("{}", res.into_resp().doc);
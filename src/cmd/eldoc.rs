@@ -0,0 +1,56 @@
+use crate::cmd;
+use crate::nrepl;
+use crate::nrepl::ops;
+use crate::nrepl::session;
+use crate::nrepl::NreplOp;
+use clap::{clap_app, App, ArgMatches};
+use std::path::Path;
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    clap_app!(eldoc =>
+        (about: "Shows arglists, type and the first line of doc for SYMBOL, cheaper than `doc`")
+        (@arg FILE: +required "FILE with NS containing SYMBOL")
+        (@arg SYMBOL: +required "SYMBOL")
+        (@arg JSON: --json "Prints JSON object with name, ns, type, arglists and docstring")
+    )
+}
+
+pub fn run(matches: &ArgMatches, nrepl_stream: &nrepl::NreplStream) {
+    let file = cmd::die_if_err(Path::new(matches.value_of("FILE").unwrap()).canonicalize());
+    let symbol = matches.value_of("SYMBOL").unwrap().to_string();
+    let session = cmd::die_if_err(session::get_existing_session_id(nrepl_stream));
+
+    let ns = cmd::die_if_err(
+        ops::GetNsName::new(file.to_string_lossy().into_owned(), session.clone())
+            .send(nrepl_stream),
+    )
+    .unwrap_or_else(|| "user".to_string());
+    let info = cmd::die_if_err(ops::Eldoc::new(session, ns, symbol).send(nrepl_stream));
+
+    let mut info = match info {
+        Some(info) => info,
+        None if matches.is_present("JSON") => return println!("null"),
+        None => return cmd::print_parseable(&vec![("is-empty", "TRUE".to_string())]),
+    };
+    info.docstring = info
+        .docstring
+        .as_deref()
+        .map(|doc| cmd::first_doc_line(doc).to_string());
+
+    if matches.is_present("JSON") {
+        println!("{}", cmd::die_if_err(serde_json::to_string(&info)));
+        return;
+    }
+
+    let name = match (&info.ns, &info.name) {
+        (Some(ns), Some(name)) => format!("{}/{}", ns, name),
+        (_, name) => name.clone().unwrap_or_default(),
+    };
+
+    cmd::print_parseable(&vec![
+        ("name", name),
+        ("type", info.kind),
+        ("arglists", info.arglists.join(" ")),
+        ("doc", info.docstring.unwrap_or_default()),
+    ]);
+}
@@ -0,0 +1,155 @@
+use crate::backend::{self, Backend, Source};
+use crate::cmd;
+use crate::nrepl;
+use crate::nrepl::ops::{EvalMsg, EvalResult};
+use crate::nrepl::session;
+use clap::{clap_app, App, ArgMatches};
+use std::io::{Read, Write};
+
+struct Opts {
+    code: String,
+    ns: Option<String>,
+    source: Source,
+    json: bool,
+}
+
+fn read_stdin() -> String {
+    let mut code = String::new();
+    cmd::die_if_err(std::io::stdin().read_to_string(&mut code));
+    code
+}
+
+impl Opts {
+    fn parse(matches: &ArgMatches) -> Opts {
+        let file = matches.value_of("FILE").map(|f| f.to_string());
+        let code = match (matches.value_of("CODE"), &file) {
+            (Some("-"), _) | (None, None) => read_stdin(),
+            (Some(code), _) => code.to_string(),
+            (None, Some(file)) => cmd::die_if_err(std::fs::read_to_string(file)),
+        };
+        let int = |name: &str| {
+            matches.value_of(name).map(|s| match s.parse::<i64>() {
+                Ok(n) => n,
+                _ => cmd::die_err(&format!("Bad {} value: {}", name, s)),
+            })
+        };
+
+        Opts {
+            code,
+            ns: matches.value_of("NS").map(|ns| ns.to_string()),
+            source: Source {
+                file,
+                line: int("LINE"),
+                column: int("COLUMN"),
+            },
+            json: matches.is_present("JSON"),
+        }
+    }
+}
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    clap_app!(eval =>
+        (about: "Evaluates CODE, prints its output and values, exits with 1 when it throws")
+        (@arg CODE: "Code to evaluate, - for stdin, contents of FILE or stdin when not given")
+        (@arg FILE: +takes_value -f --file "File the code comes from, for positions in errors")
+        (@arg NS: +takes_value -n --ns "Namespace to evaluate in, the session's current one by default")
+        (@arg LINE: +takes_value -l --line "Line of FILE where the code starts")
+        (@arg COLUMN: +takes_value -c --column "Column of FILE where the code starts, prepl ignores it")
+        (@arg JSON: --json "Prints JSON object with values, out, err, ex, root-ex and ns when it's done")
+    )
+}
+
+/// Output goes as it arrives, values are printed one per line
+fn print_msg(msg: &EvalMsg) {
+    if let Some(out) = &msg.out {
+        print!("{}", out);
+        let _ = std::io::stdout().flush();
+    }
+    if let Some(err) = &msg.err {
+        eprint!("{}", err);
+    }
+    if let Some(value) = &msg.value {
+        println!("{}", value);
+    }
+}
+
+/// Everything at once, when output isn't streamed
+fn print_result(result: &EvalResult) {
+    print!("{}", result.out);
+    eprint!("{}", result.err);
+    for value in &result.values {
+        println!("{}", value);
+    }
+}
+
+/// Sends `op` which is answered like `eval`, output is printed as it arrives unless it's `quiet`.
+/// Exits when the op fails or is interrupted.
+pub(crate) fn stream<T: Into<nrepl::Op>>(
+    nrepl_stream: &nrepl::NreplStream,
+    op: T,
+    quiet: bool,
+) -> EvalResult {
+    // Evaluation could take a while, so only the overall timeout applies
+    let timeouts = nrepl::Timeouts {
+        idle: None,
+        ..nrepl_stream.timeouts()
+    };
+    let pending = cmd::die_if_err(nrepl_stream.send_with_timeouts(op, timeouts));
+    let mut status = nrepl::Status::default();
+    let mut result = EvalResult::default();
+
+    cmd::interrupt_on_ctrl_c(&pending);
+
+    for resp in pending {
+        let resp = cmd::die_if_err(resp);
+        status.extend(nrepl::Status::of(&resp));
+
+        let msg = cmd::die_if_err(EvalMsg::read(resp));
+        if !quiet {
+            print_msg(&msg);
+        }
+        result.add(msg);
+    }
+
+    if status.is_interrupted() {
+        cmd::die_err("Interrupted");
+    }
+    if status.is_error() {
+        cmd::die_err(&format!("ERROR: Unexpected nrepl status: {}", status));
+    }
+
+    result
+}
+
+/// nrepl streams output as it's printed, forwards input and stops evaluation on Ctrl-C.
+/// Other backends print everything once evaluation is done.
+pub fn run(matches: &ArgMatches, backend: &dyn Backend, nrepl_stream: Option<&nrepl::NreplStream>) {
+    let opts = Opts::parse(matches);
+
+    let result = match nrepl_stream {
+        Some(n) => {
+            let session = cmd::die_if_err(session::get_existing_session_id(n));
+            let op = backend::eval_op(session, &opts.code, opts.ns.as_deref(), &opts.source);
+
+            stream(n, &op, opts.json)
+        }
+        None => {
+            let result =
+                cmd::die_if_err(backend.eval(&opts.code, opts.ns.as_deref(), &opts.source));
+            if !opts.json {
+                print_result(&result);
+            }
+            result
+        }
+    };
+
+    if opts.json {
+        println!("{}", cmd::die_if_err(serde_json::to_string(&result)));
+    } else if let (Some(ex), true) = (&result.ex, result.err.is_empty()) {
+        eprintln!("{}", ex);
+    }
+
+    if result.ex.is_some() {
+        std::process::exit(1);
+    }
+}
@@ -1,8 +1,6 @@
+use crate::backend::Backend;
 use crate::cmd;
-use crate::nrepl;
 use crate::nrepl::ops;
-use crate::nrepl::session;
-use crate::nrepl::NreplOp;
 use clap::{clap_app, App, ArgMatches};
 use failure::Fail;
 use std::path::Path;
@@ -50,7 +48,7 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
 fn parse_file(path: String) -> Result<File, FileError> {
     let parts: Vec<&str> = path.split(":").collect();
 
-    let first_part = parts.get(0).unwrap();
+    let first_part = parts.first().unwrap();
 
     match *first_part {
         "jar" => {
@@ -64,7 +62,7 @@ fn parse_file(path: String) -> Result<File, FileError> {
             }
 
             Ok(File::Jar {
-                jar: jar_parts.get(0).unwrap().to_string(),
+                jar: jar_parts.first().unwrap().to_string(),
                 file: f,
             })
         }
@@ -73,17 +71,15 @@ fn parse_file(path: String) -> Result<File, FileError> {
     }
 }
 
-pub fn run(matches: &ArgMatches, nrepl_stream: &nrepl::NreplStream) {
+pub fn run(matches: &ArgMatches, backend: &dyn Backend) {
     let opts = Opts::parse(matches);
-    let session = cmd::die_if_err(session::get_existing_session_id(nrepl_stream));
-    let ns = cmd::die_if_err(ops::GetNsName::new(opts.file, session.clone()).send(nrepl_stream));
+    let ns = cmd::die_if_err(backend.ns_name(&opts.file));
 
     if ns.is_none() {
         cmd::die_err("File doesn't have NS declaration");
     }
 
-    let op = ops::Info::new(session, ns.unwrap(), opts.symbol);
-    let res = cmd::die_if_err(op.send(nrepl_stream));
+    let res = cmd::die_if_err(backend.info(&ns.unwrap(), &opts.symbol));
 
     if let Some(res) = res {
         match res {
@@ -0,0 +1,21 @@
+use crate::cmd;
+use crate::nrepl;
+use crate::nrepl::ops;
+use crate::nrepl::session;
+use crate::nrepl::NreplOp;
+use clap::{clap_app, App, ArgMatches};
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    clap_app!(interrupt =>
+        (about: "Interrupts evaluation running in the session")
+        (@arg ID: +takes_value --id "Message id of the evaluation, the latest one by default")
+    )
+}
+
+pub fn run(matches: &ArgMatches, nrepl_stream: &nrepl::NreplStream) {
+    let id = matches.value_of("ID").map(|id| id.to_string());
+    let session = cmd::die_if_err(session::get_existing_session_id(nrepl_stream));
+    let status = cmd::die_if_err(ops::Interrupt::new(session, id).send(nrepl_stream));
+
+    cmd::print_parseable(&vec![("status", status.name())]);
+}
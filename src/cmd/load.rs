@@ -0,0 +1,62 @@
+use crate::cmd;
+use crate::cmd::eval;
+use crate::diagnostic::{self, Diagnostic};
+use crate::nrepl;
+use crate::nrepl::ops::{self, EvalResult};
+use crate::nrepl::session;
+use clap::{clap_app, App, ArgMatches};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize)]
+struct LoadResult {
+    #[serde(flatten)]
+    result: EvalResult,
+    diagnostics: Vec<Diagnostic>,
+}
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    clap_app!(load =>
+        (about: "Loads FILE into the REPL, compile errors are printed as FILE:LINE:COLUMN: MESSAGE")
+        (@arg FILE: +required "Clojure file")
+        (@arg JSON: --json "Prints JSON object like `eval` does, with diagnostics in addition")
+    )
+}
+
+pub fn run(matches: &ArgMatches, nrepl_stream: &nrepl::NreplStream) {
+    let path = cmd::die_if_err(Path::new(matches.value_of("FILE").unwrap()).canonicalize());
+    let json = matches.is_present("JSON");
+    let contents = cmd::die_if_err(std::fs::read_to_string(&path));
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let session = cmd::die_if_err(session::get_existing_session_id(nrepl_stream));
+    let op = ops::LoadFile::new(
+        session,
+        contents,
+        path.to_string_lossy().into_owned(),
+        file_name,
+    );
+
+    let result = eval::stream(nrepl_stream, &op, json);
+    let diagnostics = diagnostic::parse(&result.err);
+    let failed = result.ex.is_some();
+
+    if json {
+        let result = LoadResult {
+            result,
+            diagnostics,
+        };
+        println!("{}", cmd::die_if_err(serde_json::to_string(&result)));
+    } else {
+        for d in diagnostics {
+            println!("{}", d);
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
@@ -1,6 +1,8 @@
+use crate::bencode;
+use crate::cmd;
 use crate::nrepl;
 use clap::{clap_app, App, ArgMatches};
-use serde_json::error as json_error;
+use serde_bencode::value::Value as BencodeValue;
 use serde_json::value::Value as JsonValue;
 use std::collections::HashMap;
 use std::fmt;
@@ -8,6 +10,7 @@ use std::fmt;
 #[derive(Debug)]
 enum OptsParseError {
     BadOpArg(String),
+    BadJson(String),
 }
 
 impl fmt::Display for OptsParseError {
@@ -17,6 +20,7 @@ impl fmt::Display for OptsParseError {
             "OptsParseError: {}",
             match self {
                 OptsParseError::BadOpArg(op_arg) => format!("Bad op arg: {}", op_arg),
+                OptsParseError::BadJson(msg) => format!("Bad json: {}", msg),
             }
         )
     }
@@ -26,26 +30,68 @@ impl fmt::Display for OptsParseError {
 struct Opts {
     op: String,
 
-    op_args: Vec<(String, String)>,
+    id: Option<String>,
+
+    op_args: Vec<(String, BencodeValue)>,
 }
 
-pub fn to_json_string(resp: &nrepl::Resp) -> Result<String, json_error::Error> {
+/// Byte strings which aren't UTF-8 are written as `{"base64": "..."}`
+pub fn to_json_string(resp: &nrepl::Resp) -> Result<String, bencode::Error> {
     let mut hm: HashMap<String, JsonValue> = HashMap::new();
 
     for (k, v) in resp.iter() {
-        hm.insert(
-            k.to_string(),
-            crate::bencode::to_json_value(v.clone()).unwrap(),
-        );
+        hm.insert(k.to_string(), bencode::to_json_value(v.clone())?);
     }
 
-    serde_json::to_string(&hm)
+    // Keys are strings, so it can't fail
+    Ok(serde_json::to_string(&hm).unwrap())
 }
 
 impl Opts {
+    /// `{"op": "eval", "code": "(+ 1 2)", "nrepl.middleware.print/options": {...}}`
+    fn parse_json(json: &str) -> Result<Opts, OptsParseError> {
+        let bad_json = |msg: String| OptsParseError::BadJson(msg);
+        let json: JsonValue = serde_json::from_str(json).map_err(|e| bad_json(e.to_string()))?;
+
+        let msg = match bencode::from_json_value(json).map_err(|e| bad_json(e.to_string()))? {
+            Some(BencodeValue::Dict(msg)) => msg,
+            _ => return Err(bad_json("expected an object".to_string())),
+        };
+
+        let mut opts = Opts {
+            op: String::new(),
+            id: None,
+            op_args: vec![],
+        };
+
+        // `{"base64": "..."}` values could be anything
+        let utf8 = |bs: Vec<u8>| String::from_utf8(bs).map_err(|e| bad_json(e.to_string()));
+
+        for (k, v) in msg {
+            let k = utf8(k)?;
+
+            match (k.as_str(), v) {
+                ("op", BencodeValue::Bytes(op)) => opts.op = utf8(op)?,
+                ("id", BencodeValue::Bytes(id)) => opts.id = Some(utf8(id)?),
+                ("op", _) | ("id", _) => return Err(bad_json(format!("`{}` must be a string", k))),
+                (_, v) => opts.op_args.push((k, v)),
+            }
+        }
+
+        if opts.op.is_empty() {
+            return Err(bad_json("`op` is missing".to_string()));
+        }
+
+        Ok(opts)
+    }
+
     fn parse(matches: &ArgMatches) -> Result<Opts, OptsParseError> {
+        if let Some(json) = matches.value_of("JSON") {
+            return Opts::parse_json(json);
+        }
+
         let op = matches.value_of("OP").unwrap();
-        let op_args: Vec<(String, String)> = matches
+        let op_args: Vec<(String, BencodeValue)> = matches
             .values_of("OP_ARG")
             .map(|v| v.collect())
             .unwrap_or(vec![])
@@ -53,14 +99,18 @@ impl Opts {
             .map(|v| {
                 let parts = v.split("=").collect::<Vec<&str>>();
                 match parts.len() {
-                    2 => Ok((parts[0].to_string(), parts[1].to_string())),
+                    2 => Ok((
+                        parts[0].to_string(),
+                        BencodeValue::Bytes(parts[1].as_bytes().to_vec()),
+                    )),
                     _ => Err(OptsParseError::BadOpArg(parts.join("="))),
                 }
             })
-            .collect::<Result<Vec<(String, String)>, OptsParseError>>()?;
+            .collect::<Result<Vec<(String, BencodeValue)>, OptsParseError>>()?;
 
         let opts = Opts {
             op: op.to_string(),
+            id: None,
             op_args,
         };
 
@@ -71,18 +121,41 @@ impl Opts {
 pub fn app<'a, 'b>() -> App<'a, 'b> {
     clap_app!(op =>
         (about: "Sends OP to Nrepl and produces JSON output for response")
-        (@arg OP: +required "Op to send")
+        (@arg OP: required_unless[JSON] "Op to send")
         (@arg OP_ARG: ... "Op Argument")
+        (@arg JSON: +takes_value --json conflicts_with[OP] "Whole message as JSON object, for arguments which aren't strings")
     )
 }
 
 pub fn run(matches: &ArgMatches, nrepl_stream: &nrepl::NreplStream) {
     match Opts::parse(matches) {
         Ok(opts) => {
-            let op = nrepl::Op::new(opts.op, opts.op_args);
+            let mut op = nrepl::Op::new(&opts.op);
+            if let Some(id) = opts.id {
+                op.set_id(id);
+            }
+            for (k, v) in opts.op_args {
+                op.set_value(&k, v);
+            }
+            // Evaluation could take a while, responses are printed as they arrive, so only the
+            // overall timeout applies
+            let timeouts = nrepl::Timeouts {
+                idle: None,
+                ..nrepl_stream.timeouts()
+            };
+            let pending = cmd::die_if_err(nrepl_stream.send_with_timeouts(op, timeouts));
+            let mut status = nrepl::Status::default();
+
+            cmd::interrupt_on_ctrl_c(&pending);
+
+            for resp in pending {
+                let resp = cmd::die_if_err(resp);
+                status.extend(nrepl::Status::of(&resp));
+                println!("{}", cmd::die_if_err(to_json_string(&resp)));
+            }
 
-            for resp in nrepl_stream.op(op).unwrap().into_resps() {
-                println!("{}", to_json_string(&resp).unwrap());
+            if status.is_interrupted() {
+                cmd::die_err("Interrupted");
             }
         }
         Err(e) => eprintln!("Parse error: {}", e),
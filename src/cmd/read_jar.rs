@@ -1,5 +1,8 @@
 use crate::cmd;
+use crate::daemon;
 use crate::jar;
+use crate::nrepl;
+use crate::nrepl::NreplOp;
 use clap::{clap_app, App, ArgMatches};
 
 pub fn app<'a, 'b>() -> App<'a, 'b> {
@@ -10,11 +13,15 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
     )
 }
 
-pub fn run(matches: &ArgMatches) {
+/// JAR is read by the daemon when `nrepl_stream` goes through it
+pub fn run(matches: &ArgMatches, nrepl_stream: Option<&nrepl::NreplStream>) {
     let jar = matches.value_of("JAR").unwrap().to_string();
     let file = matches.value_of("FILE").unwrap().to_string();
 
-    let contents = cmd::die_if_err(jar::read_jar_file(jar, file));
+    let contents = match nrepl_stream {
+        Some(n) if n.is_relayed() => cmd::die_if_err(daemon::ReadJar::new(jar, file).send(n)),
+        _ => cmd::die_if_err(jar::read_jar_file(jar, file)),
+    };
 
     println!("{}", contents);
 }
@@ -0,0 +1,26 @@
+use crate::cmd;
+use crate::nrepl::replay;
+use clap::{clap_app, App, ArgMatches};
+use std::net::TcpListener;
+use std::path::Path;
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    clap_app!(replay =>
+        (about: "Serves nrepl session recorded with `--trace`, for testing without nrepl")
+        (@arg FIXTURE: +takes_value +required "`--trace` log to answer requests from")
+        (@arg LISTEN: +takes_value -l --listen "Address to listen on, free port of localhost by default")
+    )
+}
+
+pub fn run(matches: &ArgMatches) {
+    let fixture = cmd::die_if_err(replay::Fixture::load(Path::new(
+        matches.value_of("FIXTURE").unwrap(),
+    )));
+    let listener = cmd::die_if_err(TcpListener::bind(
+        matches.value_of("LISTEN").unwrap_or("127.0.0.1:0"),
+    ));
+
+    eprintln!("Listening on {}", cmd::die_if_err(listener.local_addr()));
+
+    cmd::die_if_err(replay::serve(listener, fixture));
+}
@@ -1,6 +1,9 @@
+//! Configuration-related facilities
+
 use failure::Error as StdError;
 use lazy_static::lazy_static;
 use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use serde::Deserialize;
 use std::cell::RefCell;
 use std::collections::HashSet;
 use std::convert::From;
@@ -24,8 +27,6 @@ thread_local! {
     static DB: RefCell<Connection> = RefCell::new(open_db_connection().unwrap());
 }
 
-///! Configuration-related facilities
-
 #[derive(Debug, failure::Fail)]
 pub enum Error {
     #[fail(display = "failed to parse sessions: {}", error)]
@@ -33,6 +34,12 @@ pub enum Error {
 
     #[fail(display = "had problems with reading sessions file: {}", ioerr)]
     SessionsReadError { ioerr: std::io::Error },
+
+    #[fail(display = "failed to parse config file: {}", error)]
+    ConfigParseError { error: serde_json::Error },
+
+    #[fail(display = "had problems with reading config file: {}", ioerr)]
+    ConfigReadError { ioerr: std::io::Error },
 }
 
 impl From<serde_json::Error> for Error {
@@ -65,6 +72,55 @@ pub fn ensure_config_dir() -> Result<(), std::io::Error> {
     Ok(())
 }
 
+/// Settings from `config.json` in config directory, every one of them is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    /// Seconds
+    pub connect_timeout: Option<f64>,
+    /// Seconds, zero means no limit
+    pub read_timeout: Option<f64>,
+    /// Seconds, zero means no limit
+    pub idle_timeout: Option<f64>,
+    /// Used when connecting with `--tls`
+    pub tls: TlsConfig,
+    /// `bencode`, `edn` or `auto`
+    pub transport: Option<String>,
+}
+
+/// PEM files for TLS connections to nrepl.
+///
+/// nREPL's own `--tls-keys-file` holds all three of them, so the same file can be given for
+/// each one. Its `.nrepl-tls` and Java key stores aren't read, paths are set only here.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// CA certificate nrepl's certificate is signed with
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate, for nrepl which authenticates its clients
+    pub cert_file: Option<PathBuf>,
+    /// Private key of the client certificate
+    pub key_file: Option<PathBuf>,
+    /// Name to verify nrepl's certificate against, the host name or IP address it was given
+    /// with by default
+    pub server_name: Option<String>,
+}
+
+fn config_file_path() -> PathBuf {
+    let mut dir = config_path();
+    dir.push("config.json");
+    dir
+}
+
+/// Reads config file, it's fine if there's none
+pub fn load_config() -> Result<Config, Error> {
+    match std::fs::read_to_string(config_file_path()) {
+        Ok(s) => serde_json::from_str(&s).map_err(|error| Error::ConfigParseError { error }),
+        Err(ioerr) if ioerr.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(ioerr) => Err(Error::ConfigReadError { ioerr }),
+    }
+}
+
 fn db_path() -> PathBuf {
     let mut dir = config_path();
     dir.push("db.sqlite");
@@ -160,7 +216,7 @@ pub fn load_session(addr: String) -> Result<Option<Session>, StdError> {
                     row.get(0)?,
                     row.get(1)?,
                     row.get::<usize, String>(2)?
-                        .split(",")
+                        .split(',')
                         .map(|s| s.to_string())
                         .collect(),
                 ))
@@ -187,6 +243,10 @@ impl Session {
         self.session.to_string()
     }
 
+    pub fn ops(&self) -> &HashSet<String> {
+        &self.ops
+    }
+
     pub fn is_op_available(&self, op: &str) -> bool {
         self.ops.contains(op)
     }
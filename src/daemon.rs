@@ -0,0 +1,433 @@
+//! Background process which keeps nrepl connections, sessions and opened JARs between CLI
+//! invocations.
+//!
+//! CLI talks to the daemon over unix socket using the same bencode messages as nrepl. Each op
+//! carries `unrepl.daemon/addr` argument, so the daemon knows where to forward it.
+
+use crate::bencode as bc;
+use crate::config;
+use crate::config::Session;
+use crate::jar;
+use crate::nrepl;
+use crate::nrepl::ops;
+use crate::nrepl::session;
+use crate::nrepl::trace::Trace;
+use crate::nrepl::NreplOp;
+use failure::{Error as StdError, Fail};
+use serde::Deserialize;
+use serde_bencode::value::Value as BencodeValue;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use zip::ZipArchive;
+
+/// Op argument telling the daemon which nrepl the op is meant for
+pub const ADDR_ARG: &str = "unrepl.daemon/addr";
+/// Op argument with nrepl's transport: `bencode`, `edn` or `auto`
+pub const CODEC_ARG: &str = "unrepl.daemon/codec";
+
+const ERROR_STATUS: &str = "unrepl.daemon/error";
+const SESSION_OP: &str = "unrepl.daemon/session";
+const READ_JAR_OP: &str = "unrepl.daemon/read-jar";
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "daemon is already running at {:?}", path)]
+    AlreadyRunning { path: PathBuf },
+    #[fail(display = "`{}` request has no `{}` argument", op, arg)]
+    MissingArg { op: String, arg: String },
+    #[fail(display = "bad nrepl address: {}", addr)]
+    BadAddr { addr: String },
+}
+
+/// Returns path of the daemon's unix socket
+pub fn socket_path() -> PathBuf {
+    let mut path = config::config_path();
+    path.push("daemon.sock");
+    path
+}
+
+/// Checks if there's a daemon accepting connections
+pub fn is_running() -> bool {
+    UnixStream::connect(socket_path()).is_ok()
+}
+
+/// `nrepl::Relay` for the daemon listening on `socket`
+pub struct Daemon {
+    socket: PathBuf,
+}
+
+impl nrepl::Relay for Daemon {
+    fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// Tell the daemon where ops should be forwarded
+    fn op_args(&self, n: &nrepl::NreplStream) -> Vec<(String, String)> {
+        vec![
+            (ADDR_ARG.to_string(), n.addr_string()),
+            (
+                CODEC_ARG.to_string(),
+                n.codec().map(|c| c.name()).unwrap_or("auto").to_string(),
+            ),
+        ]
+    }
+
+    /// Turns the daemon's failure response into an error
+    fn check_resp(&self, resp: &nrepl::Resp) -> Result<(), nrepl::Error> {
+        if nrepl::Status::of(resp).contains(ERROR_STATUS) {
+            let msg = resp
+                .get("err")
+                .and_then(|err| bc::try_into_string(err.clone()).ok())
+                .unwrap_or_default();
+
+            return Err(nrepl::Error::DaemonError { msg });
+        }
+
+        Ok(())
+    }
+
+    fn session(&self, n: &nrepl::NreplStream) -> Result<Session, StdError> {
+        GetSession::new().send(n)
+    }
+}
+
+/// Talks to nrepl at `addr` through the daemon listening on `socket`
+pub fn connect(addr: &nrepl::Addr, socket: &Path) -> Result<nrepl::NreplStream, nrepl::Error> {
+    let daemon = Daemon {
+        socket: socket.to_path_buf(),
+    };
+    nrepl::NreplStream::via_relay(addr, Arc::new(daemon))
+}
+
+#[derive(Default)]
+struct State {
+    /// By address and transport
+    streams: Mutex<HashMap<(String, String), Arc<nrepl::NreplStream>>>,
+    sessions: Mutex<HashMap<String, Session>>,
+    jars: Mutex<HashMap<String, ZipArchive<File>>>,
+    /// Messages of all nrepls go to the same log
+    trace: Option<Trace>,
+}
+
+impl State {
+    fn stream(&self, addr: &str, codec: &str) -> Result<Arc<nrepl::NreplStream>, StdError> {
+        let mut streams = self.streams.lock().unwrap();
+        let key = (addr.to_string(), codec.to_string());
+
+        if let Some(stream) = streams.get(&key) {
+            return Ok(stream.clone());
+        }
+
+        let nrepl_addr: nrepl::Addr = addr.parse().map_err(|_| Error::BadAddr {
+            addr: addr.to_string(),
+        })?;
+        let mut stream = nrepl::NreplStream::persistent(&nrepl_addr)?;
+        stream.set_tls(config::load_config()?.tls);
+        stream.set_codec(match codec {
+            "auto" => None,
+            codec => Some(codec.parse()?),
+        });
+        if let Some(trace) = &self.trace {
+            stream.set_trace(trace.clone());
+        }
+        let stream = Arc::new(stream);
+
+        streams.insert(key, stream.clone());
+
+        Ok(stream)
+    }
+
+    fn session(&self, addr: &str, codec: &str) -> Result<Session, StdError> {
+        if let Some(session) = self.sessions.lock().unwrap().get(addr) {
+            return Ok(session.clone());
+        }
+
+        let session = session::get_existing_session_id(&*self.stream(addr, codec)?)?;
+
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(addr.to_string(), session.clone());
+
+        Ok(session)
+    }
+
+    /// Known session will be validated again, nrepl could've been restarted
+    fn forget_session(&self, addr: &str) {
+        self.sessions.lock().unwrap().remove(addr);
+    }
+
+    fn read_jar(&self, jar_path: &str, file: &str) -> Result<String, StdError> {
+        let mut jars = self.jars.lock().unwrap();
+
+        let zip = match jars.entry(jar_path.to_string()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(jar::open_jar(jar_path)?),
+        };
+
+        jar::read_file(zip, file)
+    }
+}
+
+fn take_str(req: &mut nrepl::Resp, op: &str, arg: &str) -> Result<String, StdError> {
+    let val = req.remove(arg).ok_or_else(|| Error::MissingArg {
+        op: op.to_string(),
+        arg: arg.to_string(),
+    })?;
+
+    Ok(bc::try_into_string(val)?)
+}
+
+/// Clients which don't send it talk to bencode nrepl
+fn take_codec(req: &mut nrepl::Resp) -> String {
+    req.remove(CODEC_ARG)
+        .and_then(|val| bc::try_into_string(val).ok())
+        .unwrap_or_else(|| nrepl::Codec::Bencode.name().to_string())
+}
+
+fn done_resp(fields: Vec<(&str, BencodeValue)>) -> nrepl::Resp {
+    let mut resp: HashMap<String, BencodeValue> = fields
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+
+    resp.insert(
+        "status".to_string(),
+        BencodeValue::List(vec![BencodeValue::Bytes(b"done".to_vec())]),
+    );
+
+    resp.into()
+}
+
+fn error_resp(err: StdError) -> nrepl::Resp {
+    let mut resp = done_resp(vec![(
+        "err",
+        BencodeValue::Bytes(err.to_string().into_bytes()),
+    )]);
+
+    resp.insert(
+        "status".to_string(),
+        BencodeValue::List(vec![
+            BencodeValue::Bytes(b"done".to_vec()),
+            BencodeValue::Bytes(ERROR_STATUS.as_bytes().to_vec()),
+        ]),
+    );
+
+    resp
+}
+
+/// Responses are passed to `reply` one by one, so forwarded ops are streamed to the client
+fn handle_request(
+    state: &State,
+    mut req: nrepl::Resp,
+    reply: &mut dyn FnMut(nrepl::Resp),
+) -> Result<(), StdError> {
+    let op = take_str(&mut req, "?", "op")?;
+
+    match op.as_str() {
+        SESSION_OP => {
+            let addr = take_str(&mut req, &op, ADDR_ARG)?;
+            let codec = take_codec(&mut req);
+            let session = state.session(&addr, &codec)?;
+            let ops = session
+                .ops()
+                .iter()
+                .map(|op| BencodeValue::Bytes(op.clone().into_bytes()))
+                .collect();
+
+            reply(done_resp(vec![
+                ("session", BencodeValue::Bytes(session.id().into_bytes())),
+                ("ops", BencodeValue::List(ops)),
+            ]));
+        }
+
+        READ_JAR_OP => {
+            let jar = take_str(&mut req, &op, "jar")?;
+            let file = take_str(&mut req, &op, "file")?;
+            let contents = state.read_jar(&jar, &file)?;
+
+            reply(done_resp(vec![(
+                "contents",
+                BencodeValue::Bytes(contents.into_bytes()),
+            )]));
+        }
+
+        _ => {
+            let addr = take_str(&mut req, &op, ADDR_ARG)?;
+            let codec = take_codec(&mut req);
+            // Client's message id is kept, so `interrupt` can refer to it
+            let id = req.remove("id").and_then(|id| bc::try_into_string(id).ok());
+
+            let stream = state.stream(&addr, &codec)?;
+            // Client decides how long it's ready to wait
+            let timeouts = nrepl::Timeouts {
+                read: None,
+                idle: None,
+                ..stream.timeouts()
+            };
+            let mut nrepl_op = nrepl::Op::new(&op);
+            if let Some(id) = id {
+                nrepl_op.set_id(id);
+            }
+            for (k, v) in req.drain() {
+                nrepl_op.set_value(&k, v);
+            }
+            let pending = stream.send_with_timeouts(nrepl_op, timeouts)?;
+
+            for resp in pending {
+                let resp = resp.inspect_err(|_| state.forget_session(&addr))?;
+
+                if nrepl::Status::of(&resp).is_unknown_session() {
+                    state.forget_session(&addr);
+                }
+
+                reply(resp);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn serve_client(client: UnixStream, state: Arc<State>) {
+    let writer = match client.try_clone() {
+        Ok(w) => Arc::new(Mutex::new(w)),
+        Err(_) => return,
+    };
+    let mut r = BufReader::new(client);
+
+    // Requests are handled concurrently, client matches responses by their `id`
+    while let Ok(req) = nrepl::read_resp(&mut r) {
+        let state = state.clone();
+        let writer = writer.clone();
+
+        thread::spawn(move || {
+            let id = req.id();
+            let mut reply = |mut resp: nrepl::Resp| {
+                match &id {
+                    Some(id) => resp.insert(
+                        "id".to_string(),
+                        BencodeValue::Bytes(id.clone().into_bytes()),
+                    ),
+                    None => resp.remove("id"),
+                };
+
+                if let Ok(bencode) = serde_bencode::to_bytes(&resp) {
+                    let _ = writer.lock().unwrap().write_all(&bencode);
+                }
+            };
+
+            if let Err(e) = handle_request(&state, req, &mut reply) {
+                reply(error_resp(e));
+            }
+        });
+    }
+}
+
+/// Listens on `path` until the process is killed, CLI looks for it at `socket_path`
+pub fn serve(path: &Path) -> Result<(), StdError> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::AlreadyRunning {
+                path: path.to_path_buf(),
+            }
+            .into());
+        }
+        // Left by daemon which wasn't stopped cleanly
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let state = Arc::new(State {
+        trace: Trace::from_env()?,
+        ..State::default()
+    });
+
+    for client in listener.incoming() {
+        let client = client?;
+        let state = state.clone();
+
+        thread::spawn(move || serve_client(client, state));
+    }
+
+    Ok(())
+}
+
+/// Asks daemon for nrepl session, it is either known to daemon or created
+#[derive(Default)]
+pub struct GetSession {}
+
+impl GetSession {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl From<&GetSession> for nrepl::Op {
+    fn from(_op: &GetSession) -> nrepl::Op {
+        nrepl::Op::new(SESSION_OP)
+    }
+}
+
+#[derive(Deserialize)]
+struct SessionResp {
+    session: String,
+    ops: Vec<String>,
+}
+
+impl nrepl::NreplOp<Session> for GetSession {
+    type Error = StdError;
+
+    fn send(&self, n: &nrepl::NreplStream) -> Result<Session, StdError> {
+        let resps = ops::check_status(n.typed_op(self)?)?;
+        let resp: SessionResp = ops::decode(SESSION_OP, resps)?;
+
+        Ok(Session::new(
+            n.addr_string(),
+            resp.session,
+            resp.ops.into_iter().collect(),
+        ))
+    }
+}
+
+/// Reads file from JAR which daemon keeps opened
+pub struct ReadJar {
+    jar: String,
+    file: String,
+}
+
+impl ReadJar {
+    pub fn new(jar: String, file: String) -> Self {
+        Self { jar, file }
+    }
+}
+
+impl From<&ReadJar> for nrepl::Op {
+    fn from(ReadJar { jar, file }: &ReadJar) -> nrepl::Op {
+        nrepl::Op::new(READ_JAR_OP)
+            .arg("jar", jar)
+            .arg("file", file)
+    }
+}
+
+#[derive(Deserialize)]
+struct ReadJarResp {
+    contents: String,
+}
+
+impl nrepl::NreplOp<String> for ReadJar {
+    type Error = StdError;
+
+    fn send(&self, n: &nrepl::NreplStream) -> Result<String, StdError> {
+        let resps = ops::check_status(n.typed_op(self)?)?;
+        let resp: ReadJarResp = ops::decode(READ_JAR_OP, resps)?;
+
+        Ok(resp.contents)
+    }
+}
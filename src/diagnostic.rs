@@ -0,0 +1,140 @@
+//! Places of compile errors found in what Clojure prints to `err`, so editors can jump to them.
+//!
+//! Clojure 1.10+ prints `Syntax error compiling at (src/my/app.clj:10:5).` followed by the
+//! message on the next line, older versions print `CompilerException ...: message,
+//! compiling:(src/my/app.clj:10:5)`. cider-nrepl sends the same text.
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    /// As the compiler knows it, the path given to `load-file` or a classpath resource
+    pub file: String,
+    pub line: i64,
+    pub column: Option<i64>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.column {
+            Some(column) => write!(
+                f,
+                "{}:{}:{}: {}",
+                self.file, self.line, column, self.message
+            ),
+            None => write!(f, "{}:{}: {}", self.file, self.line, self.message),
+        }
+    }
+}
+
+/// `path:line:column` or `path:line`
+fn parse_location(loc: &str) -> Option<(String, i64, Option<i64>)> {
+    let mut parts = loc.rsplitn(3, ':');
+    let last = parts.next()?.parse::<i64>().ok()?;
+
+    match (parts.next(), parts.next()) {
+        (Some(line), Some(file)) => match line.parse::<i64>() {
+            Ok(line) => Some((file.to_string(), line, Some(last))),
+            Err(_) => Some((format!("{}:{}", file, line), last, None)),
+        },
+        (Some(file), None) => Some((file.to_string(), last, None)),
+        _ => None,
+    }
+}
+
+/// `Syntax error compiling at (src/my/app.clj:10:5).`, message is on the next line
+fn parse_header(line: &str) -> Option<(String, i64, Option<i64>)> {
+    let start = line.find(" at (")? + " at (".len();
+    let end = line.rfind(").")?;
+
+    if end < start || !line.contains("error") {
+        return None;
+    }
+    parse_location(&line[start..end])
+}
+
+/// `CompilerException java.lang.RuntimeException: message, compiling:(src/my/app.clj:10:5)`
+fn parse_compiling(line: &str) -> Option<Diagnostic> {
+    let start = line.find(", compiling:(")?;
+    let loc = &line[start + ", compiling:(".len()..];
+    let (file, line_no, column) = parse_location(loc.trim_end().strip_suffix(')')?)?;
+    let message = &line[..start];
+    let message = message
+        .strip_prefix("CompilerException ")
+        .unwrap_or(message);
+
+    Some(Diagnostic {
+        file,
+        line: line_no,
+        column,
+        message: message.to_string(),
+    })
+}
+
+pub fn parse(err: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut lines = err.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        if let Some(d) = parse_compiling(line) {
+            diagnostics.push(d);
+        } else if let Some((file, line_no, column)) = parse_header(line) {
+            let message = lines.next_if(|l| !l.trim().is_empty()).unwrap_or(line);
+
+            diagnostics.push(Diagnostic {
+                file,
+                line: line_no,
+                column,
+                message: message.trim().to_string(),
+            });
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_errors_are_found_test() {
+        let err = "Syntax error compiling at (/home/dev/app/src/my/app/core.clj:10:5).\n\
+                   Unable to resolve symbol: foo in this context\n\
+                   Syntax error reading source at (my/app/util.clj:42:1).\n\
+                   EOF while reading, starting at line 40\n\
+                   CompilerException java.lang.RuntimeException: No such var: str/jion, \
+                   compiling:(my/app/old.clj:7:3)\n\
+                   Execution error (ArithmeticException) at my.app.core/f (core.clj:3).\n\
+                   Divide by zero\n";
+
+        assert_eq!(
+            parse(err),
+            vec![
+                Diagnostic {
+                    file: "/home/dev/app/src/my/app/core.clj".to_string(),
+                    line: 10,
+                    column: Some(5),
+                    message: "Unable to resolve symbol: foo in this context".to_string(),
+                },
+                Diagnostic {
+                    file: "my/app/util.clj".to_string(),
+                    line: 42,
+                    column: Some(1),
+                    message: "EOF while reading, starting at line 40".to_string(),
+                },
+                Diagnostic {
+                    file: "my/app/old.clj".to_string(),
+                    line: 7,
+                    column: Some(3),
+                    message: "java.lang.RuntimeException: No such var: str/jion".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            parse(err)[0].to_string(),
+            "/home/dev/app/src/my/app/core.clj:10:5: Unable to resolve symbol: foo in this context"
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! Just enough EDN for `nrepl.transport/edn` messages.
+//!
+//! Messages are converted from and to the same values as bencode ones, so the rest of unrepl
+//! doesn't care which transport nrepl uses. Keywords, symbols and characters become strings,
+//! sets and lists become lists, tags are dropped, `nil` entries are left out.
+
+use failure::Fail;
+use serde_bencode::value::Value;
+use std::collections::HashMap;
+use std::io::{BufRead, ErrorKind};
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "edn io error: {}", ioerr)]
+    IOError { ioerr: std::io::Error },
+    #[fail(display = "unexpected end of edn")]
+    UnexpectedEof,
+    #[fail(display = "unexpected `{}` in edn", ch)]
+    Unexpected { ch: char },
+    #[fail(display = "bad edn token: {}", token)]
+    BadToken { token: String },
+    #[fail(display = "edn nesting is too deep")]
+    TooDeep,
+}
+
+/// Forms are read recursively, deeper ones would overflow the reader's stack
+const MAX_DEPTH: usize = 512;
+
+impl From<std::io::Error> for Error {
+    fn from(ioerr: std::io::Error) -> Self {
+        Self::IOError { ioerr }
+    }
+}
+
+struct Reader<'a, R: BufRead> {
+    r: &'a mut R,
+    depth: usize,
+}
+
+fn is_delimiter(b: u8) -> bool {
+    b.is_ascii_whitespace() || b",;\"{}[]()".contains(&b)
+}
+
+impl<'a, R: BufRead> Reader<'a, R> {
+    fn peek(&mut self) -> Result<Option<u8>, Error> {
+        loop {
+            match self.r.fill_buf() {
+                Ok(buf) => return Ok(buf.first().copied()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn next(&mut self) -> Result<u8, Error> {
+        let b = self.peek()?.ok_or(Error::UnexpectedEof)?;
+        self.r.consume(1);
+        Ok(b)
+    }
+
+    /// Whitespace, commas and comments, the last one may end with the stream
+    fn skip_blank(&mut self) -> Result<(), Error> {
+        while let Some(b) = self.peek()? {
+            if b == b';' {
+                while !matches!(self.peek()?, None | Some(b'\n')) {
+                    self.r.consume(1);
+                }
+            } else if b.is_ascii_whitespace() || b == b',' {
+                self.r.consume(1);
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn token(&mut self) -> Result<String, Error> {
+        let mut token = vec![];
+
+        while let Some(b) = self.peek()? {
+            // Character literal, like `\(`
+            if token.is_empty() && b == b'\\' {
+                self.r.consume(1);
+                token.push(b);
+                token.push(self.next()?);
+                continue;
+            }
+            if is_delimiter(b) && !token.is_empty() {
+                break;
+            }
+            token.push(b);
+            self.r.consume(1);
+        }
+
+        Ok(String::from_utf8_lossy(&token).into_owned())
+    }
+
+    fn string(&mut self) -> Result<Value, Error> {
+        let mut bs = vec![];
+
+        loop {
+            match self.next()? {
+                b'"' => return Ok(Value::Bytes(bs)),
+                b'\\' => match self.next()? {
+                    b'n' => bs.push(b'\n'),
+                    b't' => bs.push(b'\t'),
+                    b'r' => bs.push(b'\r'),
+                    b'u' => {
+                        let mut hex = String::new();
+                        for _ in 0..4 {
+                            hex.push(self.next()? as char);
+                        }
+                        let ch = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(std::char::from_u32)
+                            .ok_or(Error::BadToken { token: hex })?;
+                        bs.extend(ch.to_string().into_bytes());
+                    }
+                    b => bs.push(b),
+                },
+                b => bs.push(b),
+            }
+        }
+    }
+
+    /// Forms until `end`, `nil`s are skipped
+    fn forms_until(&mut self, end: u8) -> Result<Vec<Value>, Error> {
+        let mut forms = vec![];
+
+        loop {
+            self.skip_blank()?;
+
+            if self.peek()? == Some(end) {
+                self.r.consume(1);
+                return Ok(forms);
+            }
+
+            if let Some(form) = self.form()? {
+                forms.push(form);
+            }
+        }
+    }
+
+    fn map(&mut self) -> Result<Value, Error> {
+        let mut map = HashMap::new();
+
+        loop {
+            self.skip_blank()?;
+
+            if self.peek()? == Some(b'}') {
+                self.r.consume(1);
+                return Ok(Value::Dict(map));
+            }
+
+            let key = self.form()?;
+            self.skip_blank()?;
+            let val = self.form()?;
+
+            if let (Some(key), Some(val)) = (key, val) {
+                map.insert(key_bytes(key), val);
+            }
+        }
+    }
+
+    fn dispatch(&mut self) -> Result<Option<Value>, Error> {
+        match self.peek()? {
+            Some(b'{') => {
+                self.r.consume(1);
+                Ok(Some(Value::List(self.forms_until(b'}')?)))
+            }
+            // `#inst "..."`, `#uuid "..."` and so on
+            _ => {
+                self.token()?;
+                self.skip_blank()?;
+                self.form()
+            }
+        }
+    }
+
+    fn atom(&mut self) -> Result<Option<Value>, Error> {
+        let token = self.token()?;
+
+        Ok(match token.as_str() {
+            "nil" => None,
+            _ if token.starts_with(':') => Some(Value::Bytes(token.as_bytes()[1..].to_vec())),
+            _ if token.starts_with('\\') => Some(Value::Bytes(
+                match &token[1..] {
+                    "newline" => "\n",
+                    "space" => " ",
+                    "tab" => "\t",
+                    "return" => "\r",
+                    ch => ch,
+                }
+                .as_bytes()
+                .to_vec(),
+            )),
+            _ => match token.trim_end_matches('N').parse::<i64>() {
+                Ok(n) => Some(Value::Int(n)),
+                // Symbols, booleans and other numbers
+                Err(_) => Some(Value::Bytes(token.into_bytes())),
+            },
+        })
+    }
+
+    fn form(&mut self) -> Result<Option<Value>, Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+
+        self.depth += 1;
+        let form = self.nested_form();
+        self.depth -= 1;
+        form
+    }
+
+    fn nested_form(&mut self) -> Result<Option<Value>, Error> {
+        match self.peek()?.ok_or(Error::UnexpectedEof)? {
+            b'"' => {
+                self.r.consume(1);
+                self.string().map(Some)
+            }
+            b'{' => {
+                self.r.consume(1);
+                self.map().map(Some)
+            }
+            b'[' => {
+                self.r.consume(1);
+                Ok(Some(Value::List(self.forms_until(b']')?)))
+            }
+            b'(' => {
+                self.r.consume(1);
+                Ok(Some(Value::List(self.forms_until(b')')?)))
+            }
+            b'#' => {
+                self.r.consume(1);
+                self.dispatch()
+            }
+            b @ b'}' | b @ b']' | b @ b')' => Err(Error::Unexpected { ch: b as char }),
+            _ => self.atom(),
+        }
+    }
+}
+
+fn key_bytes(key: Value) -> Vec<u8> {
+    match key {
+        Value::Bytes(bs) => bs,
+        Value::Int(n) => n.to_string().into_bytes(),
+        v => format!("{:?}", v).into_bytes(),
+    }
+}
+
+/// Reads single form, `None` stands for `nil`. `IOError` with `UnexpectedEof` kind means the
+/// stream has ended before the form has started.
+pub fn read<R: BufRead>(r: &mut R) -> Result<Option<Value>, Error> {
+    let mut reader = Reader { r, depth: 0 };
+
+    reader.skip_blank()?;
+
+    if reader.peek()?.is_none() {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+
+    reader.form()
+}
+
+fn is_keyword(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars()
+            .all(|c| c.is_alphanumeric() || "*+!-_?<>=/.".contains(c))
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_value(val: &Value, out: &mut String) {
+    match val {
+        Value::Bytes(bs) => write_string(&String::from_utf8_lossy(bs), out),
+        Value::Int(n) => out.push_str(&n.to_string()),
+        Value::List(vals) => {
+            out.push('[');
+            for (i, v) in vals.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write_value(v, out);
+            }
+            out.push(']');
+        }
+        Value::Dict(map) => {
+            out.push('{');
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                let k = String::from_utf8_lossy(k);
+                if is_keyword(&k) {
+                    out.push(':');
+                    out.push_str(&k);
+                } else {
+                    write_string(&k, out);
+                }
+                out.push(' ');
+                write_value(v, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+/// Dict keys are written as keywords, byte strings as strings, lists as vectors
+pub fn to_string(val: &Value) -> String {
+    let mut out = String::new();
+    write_value(val, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_nrepl_response_test() {
+        let mut edn = &br#"{:id "1", :status #{:done}, :value "\"a\\nb\"", :ns nil,
+                           :ops {:eval {}} :n -42 :at #inst "2020-03-24"}"#[..];
+
+        let resp = match read(&mut edn).unwrap() {
+            Some(Value::Dict(map)) => map,
+            v => panic!("expected map, got: {:?}", v),
+        };
+
+        assert_eq!(resp[&b"id"[..]], Value::Bytes(b"1".to_vec()));
+        assert_eq!(
+            resp[&b"status"[..]],
+            Value::List(vec![Value::Bytes(b"done".to_vec())])
+        );
+        assert_eq!(resp[&b"value"[..]], Value::Bytes(br#""a\nb""#.to_vec()));
+        assert_eq!(resp[&b"n"[..]], Value::Int(-42));
+        assert!(!resp.contains_key(&b"ns"[..]));
+        assert_eq!(resp[&b"at"[..]], Value::Bytes(b"2020-03-24".to_vec()));
+
+        match read(&mut edn) {
+            Err(Error::IOError { ioerr }) => assert_eq!(ioerr.kind(), ErrorKind::UnexpectedEof),
+            res => panic!("expected end of stream, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn trailing_comment_is_end_of_stream_test() {
+        let mut edn = &b"{:id \"1\"} ; done"[..];
+
+        assert!(read(&mut edn).unwrap().is_some());
+        match read(&mut edn) {
+            Err(Error::IOError { ioerr }) => assert_eq!(ioerr.kind(), ErrorKind::UnexpectedEof),
+            res => panic!("expected end of stream, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_refused_test() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+
+        assert!(read(&mut nested(MAX_DEPTH).as_bytes()).is_ok());
+        match read(&mut nested(MAX_DEPTH + 1).as_bytes()) {
+            Err(Error::TooDeep) => (),
+            res => panic!("expected too deep error, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn write_read_roundtrip_test() {
+        let mut map = HashMap::new();
+        map.insert(b"op".to_vec(), Value::Bytes(b"eval".to_vec()));
+        map.insert(
+            b"code".to_vec(),
+            Value::Bytes(b"(println \"hi\\\")".to_vec()),
+        );
+        map.insert(
+            b"nrepl.middleware.print/options".to_vec(),
+            Value::List(vec![Value::Int(1), Value::Bytes(vec![])]),
+        );
+        let val = Value::Dict(map);
+
+        let edn = to_string(&val);
+        assert_eq!(read(&mut edn.as_bytes()).unwrap(), Some(val));
+    }
+}
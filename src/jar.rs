@@ -1,20 +1,31 @@
+//! Helpers for dealing with JAR
+
 use failure::Error;
 use std::fs::File;
 use std::io::Read;
+use zip::ZipArchive;
 
-///! Helpers for dealing with JAR
-
-/// Reads single file from JAR package
-pub fn read_jar_file(jar_path: String, file: String) -> Result<String, Error> {
-    let mut out = String::new();
-
+/// Opens JAR package, so several files could be read from it
+pub fn open_jar(jar_path: &str) -> Result<ZipArchive<File>, Error> {
     let f = File::open(jar_path)?;
 
-    let mut zip = zip::ZipArchive::new(f)?;
+    Ok(ZipArchive::new(f)?)
+}
+
+/// Reads single file from already opened JAR package
+pub fn read_file(zip: &mut ZipArchive<File>, file: &str) -> Result<String, Error> {
+    let mut out = String::new();
 
-    let mut zip_file = zip.by_name(&file)?;
+    let mut zip_file = zip.by_name(file)?;
 
     zip_file.read_to_string(&mut out)?;
 
     Ok(out)
 }
+
+/// Reads single file from JAR package
+pub fn read_jar_file(jar_path: String, file: String) -> Result<String, Error> {
+    let mut zip = open_jar(&jar_path)?;
+
+    read_file(&mut zip, &file)
+}
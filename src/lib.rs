@@ -1,5 +1,13 @@
-pub mod nrepl;
-pub mod cmd;
+// `failure` derive macros generate impls inside anonymous consts
+#![allow(non_local_definitions)]
+
+pub mod backend;
 pub mod bencode;
+pub mod cmd;
 pub mod config;
+pub mod daemon;
+pub mod diagnostic;
+pub mod edn;
 pub mod jar;
+pub mod nrepl;
+pub mod prepl;
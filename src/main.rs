@@ -1,60 +1,230 @@
 use clap::{clap_app, ArgMatches};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+use unrepl::backend::Backend;
 use unrepl::cmd;
+use unrepl::config;
+use unrepl::daemon;
 use unrepl::nrepl;
-use unrepl::nrepl::ops;
-use unrepl::nrepl::NreplOp;
+use unrepl::nrepl::trace::Trace;
+use unrepl::prepl;
 
-fn nrepl_stream(arg: &ArgMatches) -> nrepl::NreplStream {
-    let port = if let Some(port_str) = arg.value_of("PORT") {
-        match port_str.parse::<u32>() {
-            Ok(port) => Some(port),
-            _ => cmd::die_err(&format!("Bad port value: {}", port_str)),
+/// Zero or negative seconds mean no limit
+fn secs_timeout(secs: f64) -> Option<Duration> {
+    if secs > 0.0 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+/// Command line flags take precedence over config file
+fn timeouts(arg: &ArgMatches, config: &config::Config) -> nrepl::Timeouts {
+    let secs = |name: &str, configured: Option<f64>| -> Option<f64> {
+        match arg.value_of(name) {
+            Some(s) => match s.parse::<f64>() {
+                Ok(secs) => Some(secs),
+                _ => cmd::die_err(&format!("Bad {} value: {}", name, s)),
+            },
+            None => configured,
         }
+    };
+
+    let mut timeouts = nrepl::Timeouts::default();
+
+    if let Some(s) = secs("CONNECT_TIMEOUT", config.connect_timeout) {
+        timeouts.connect = secs_timeout(s).unwrap_or(timeouts.connect);
+    }
+    if let Some(s) = secs("TIMEOUT", config.read_timeout) {
+        timeouts.read = secs_timeout(s);
+    }
+    if let Some(s) = secs("IDLE_TIMEOUT", config.idle_timeout) {
+        timeouts.idle = secs_timeout(s);
+    }
+
+    timeouts
+}
+
+/// Relative socket path is resolved against current directory, so it names the same nrepl
+/// wherever the session was saved from
+fn socket_addr(path: &str) -> nrepl::Addr {
+    let path = Path::new(path);
+
+    if path.is_absolute() {
+        nrepl::Addr::Unix(path.to_path_buf())
     } else {
-        nrepl::default_nrepl_port()
+        let cwd = cmd::die_if_err(std::env::current_dir());
+        nrepl::Addr::Unix(cwd.join(path))
+    }
+}
+
+fn nrepl_addr(arg: &ArgMatches) -> nrepl::Addr {
+    if let Some(path) = arg.value_of("SOCKET") {
+        return socket_addr(path);
+    }
+
+    let addr = match arg.value_of("PORT") {
+        Some(addr) => addr.to_string(),
+        None => match nrepl::default_nrepl_port() {
+            Some(port) => port.to_string(),
+            None => cmd::die_err("Please specify nrepl PORT or SOCKET"),
+        },
     };
 
-    if let Some(port) = port {
-        match nrepl::NreplStream::new(&nrepl::port_addr(port)) {
-            Ok(nrepl) => nrepl,
-            Err(e) => cmd::die_err(&format!("Failed to connect to nrepl: {}", e)),
-        }
+    let addr = if arg.is_present("TLS") && !addr.starts_with("tls://") {
+        format!("tls://{}", addr.trim_start_matches("nrepl://"))
     } else {
-        cmd::die_err("Please specify nrepl PORT")
+        addr
+    };
+
+    let host = arg.value_of("HOST").unwrap_or("127.0.0.1");
+
+    cmd::die_if_err(nrepl::Addr::resolve(&addr, host))
+}
+
+/// `None` means it should be detected
+fn codec(arg: &ArgMatches, config: &config::Config) -> Option<nrepl::Codec> {
+    match arg.value_of("TRANSPORT").or(config.transport.as_deref()) {
+        None => Some(nrepl::Codec::Bencode),
+        Some("auto") => None,
+        Some(codec) => Some(cmd::die_if_err(codec.parse())),
     }
 }
 
-fn show_ns(argm: &ArgMatches, n: &nrepl::NreplStream) {
+/// Evaluated code reads from terminal unless there's an input file
+fn input(arg: &ArgMatches) -> Box<dyn BufRead + Send> {
+    match arg.value_of("INPUT") {
+        Some(path) => Box::new(BufReader::new(cmd::die_if_err(File::open(path)))),
+        None => Box::new(BufReader::new(std::io::stdin())),
+    }
+}
+
+/// `--trace` takes precedence over `UNREPL_TRACE`
+fn trace(arg: &ArgMatches) -> Option<Trace> {
+    match arg.value_of("TRACE") {
+        Some(path) => Some(cmd::die_if_err(Trace::open(Path::new(path)))),
+        None => cmd::die_if_err(Trace::from_env()),
+    }
+}
+
+fn nrepl_stream(arg: &ArgMatches) -> nrepl::NreplStream {
+    let config = cmd::die_if_err(config::load_config());
+    let addr = nrepl_addr(arg);
+    let stream = if daemon::is_running() {
+        daemon::connect(&addr, &daemon::socket_path())
+    } else {
+        nrepl::NreplStream::persistent(&addr)
+    };
+
+    match stream {
+        Ok(mut nrepl) => {
+            nrepl.set_timeouts(timeouts(arg, &config));
+            nrepl.set_codec(codec(arg, &config));
+            nrepl.set_tls(config.tls);
+            nrepl.set_input(input(arg));
+            if let Some(trace) = trace(arg) {
+                nrepl.set_trace(trace);
+            }
+            nrepl
+        }
+        Err(e) => cmd::die_err(&format!("Failed to connect to nrepl: {}", e)),
+    }
+}
+
+fn prepl_stream(arg: &ArgMatches) -> prepl::PreplStream {
+    let config = cmd::die_if_err(config::load_config());
+    let mut prepl = prepl::PreplStream::new(&nrepl_addr(arg));
+
+    prepl.set_timeouts(timeouts(arg, &config));
+    prepl.set_tls(config.tls);
+    prepl
+}
+
+fn needs_nrepl<'a>(n: Option<&'a nrepl::NreplStream>, cmd_name: &str) -> &'a nrepl::NreplStream {
+    match n {
+        Some(n) => n,
+        None => cmd::die_err(&format!("`{}` works only with nrepl", cmd_name)),
+    }
+}
+
+fn show_ns(argm: &ArgMatches, backend: &dyn Backend) {
     let file = argm.value_of("FILE").unwrap();
-    let session = cmd::die_if_err(unrepl::nrepl::session::get_existing_session_id(n));
-    let op = ops::GetNsName::new(file.to_string(), session);
-    println!("NS: {}", op.send(n).unwrap().unwrap());
+    println!("NS: {}", backend.ns_name(file).unwrap().unwrap());
 }
 
 fn main() {
     unrepl::config::ensure_config_dir().unwrap();
-    unrepl::config::ensure_migrations().unwrap();
 
     let mut app = clap_app!(unrepl =>
         (version: "0.1")
         (author: "Michael Lutsiuk <michael.lutsiuk@gmail.com>")
-        (@arg PORT: +takes_value -p --port "Nrepl port")
+        (@arg PORT: +takes_value -p --port "Nrepl port, host:port, nrepl://host:port or tls://host:port")
+        (@arg HOST: +takes_value -H --host "Nrepl host for bare port, 127.0.0.1 by default")
+        (@arg SOCKET: +takes_value -s --socket conflicts_with[PORT] "Nrepl unix socket path")
+        (@arg TRANSPORT: +takes_value --transport "Nrepl transport: bencode (default), edn or auto")
+        (@arg PREPL: --prepl conflicts_with[TRANSPORT] "Address is clojure.core.server/io-prepl, not nrepl")
+        (@arg TLS: --tls conflicts_with[SOCKET] "Connect over TLS with PEM files set in config.json, nREPL's .nrepl-tls isn't read")
+        (@arg INPUT: +takes_value -i --input "File evaluated code reads input from, terminal by default")
+        (@arg TIMEOUT: +takes_value -t --timeout "Seconds to wait for op to be done, 0 for no limit")
+        (@arg IDLE_TIMEOUT: +takes_value --("idle-timeout") "Seconds to wait for each next response, 0 for no limit")
+        (@arg CONNECT_TIMEOUT: +takes_value --("connect-timeout") "Seconds to wait for connection")
+        (@arg TRACE: +takes_value --trace "Appends every nrepl message to FILE, see `decode`")
     )
     .subcommand(clap_app!(show_ns => (@arg FILE: +takes_value "File")))
     .subcommand(cmd::op::app())
     .subcommand(cmd::find_def::app())
     .subcommand(cmd::read_jar::app())
-    .subcommand(cmd::doc::app());
+    .subcommand(cmd::doc::app())
+    .subcommand(cmd::eval::app())
+    .subcommand(cmd::load::app())
+    .subcommand(cmd::complete::app())
+    .subcommand(cmd::eldoc::app())
+    .subcommand(cmd::apropos::app())
+    .subcommand(cmd::interrupt::app())
+    .subcommand(cmd::daemon::app())
+    .subcommand(cmd::decode::app())
+    .subcommand(cmd::replay::app());
 
     let matches = app.clone().get_matches();
-    let nrepl_stream = nrepl_stream(&matches);
 
     match matches.subcommand() {
-        ("op", Some(argm)) => cmd::op::run(&argm, &nrepl_stream),
-        ("find_def", Some(argm)) => cmd::find_def::run(&argm, &nrepl_stream),
-        ("doc", Some(argm)) => cmd::doc::run(&argm, &nrepl_stream),
-        ("show_ns", Some(argm)) => show_ns(&argm, &nrepl_stream),
-        ("read_jar", Some(argm)) => cmd::read_jar::run(&argm),
+        ("daemon", Some(argm)) => return cmd::daemon::run(argm),
+        ("decode", Some(argm)) => return cmd::decode::run(argm),
+        ("replay", Some(argm)) => return cmd::replay::run(argm),
+        _ => (),
+    }
+
+    let prepl_stream;
+    let nrepl_stream;
+    let (backend, nrepl_stream): (&dyn Backend, _) = if matches.is_present("PREPL") {
+        prepl_stream = self::prepl_stream(&matches);
+        (&prepl_stream, None)
+    } else {
+        nrepl_stream = self::nrepl_stream(&matches);
+
+        // Daemon takes care of sessions database
+        if !nrepl_stream.is_relayed() {
+            unrepl::config::ensure_migrations().unwrap();
+        }
+        (&nrepl_stream, Some(&nrepl_stream))
+    };
+
+    match matches.subcommand() {
+        ("op", Some(argm)) => cmd::op::run(argm, needs_nrepl(nrepl_stream, "op")),
+        ("find_def", Some(argm)) => cmd::find_def::run(argm, backend),
+        ("doc", Some(argm)) => cmd::doc::run(argm, backend),
+        ("eval", Some(argm)) => cmd::eval::run(argm, backend, nrepl_stream),
+        ("load", Some(argm)) => cmd::load::run(argm, needs_nrepl(nrepl_stream, "load")),
+        ("complete", Some(argm)) => cmd::complete::run(argm, needs_nrepl(nrepl_stream, "complete")),
+        ("eldoc", Some(argm)) => cmd::eldoc::run(argm, needs_nrepl(nrepl_stream, "eldoc")),
+        ("apropos", Some(argm)) => cmd::apropos::run(argm, needs_nrepl(nrepl_stream, "apropos")),
+        ("show_ns", Some(argm)) => show_ns(argm, backend),
+        ("interrupt", Some(argm)) => {
+            cmd::interrupt::run(argm, needs_nrepl(nrepl_stream, "interrupt"))
+        }
+        ("read_jar", Some(argm)) => cmd::read_jar::run(argm, nrepl_stream),
         _ => {
             app.print_help().unwrap();
             println!("\n")
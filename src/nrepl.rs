@@ -1,18 +1,34 @@
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod ops;
+pub mod replay;
 pub mod session;
+pub mod tls;
+pub mod trace;
 
 use crate::bencode;
+use crate::config::{Session, TlsConfig};
+use crate::edn;
 use failure::Fail;
+use serde::de::DeserializeOwned;
 use serde::ser::SerializeMap;
 use serde::{Deserialize, Serialize, Serializer};
 use serde_bencode::value::Value as BencodeValue;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
 use std::convert::{From, Into, TryFrom};
 use std::fmt;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::iter::FromIterator;
-use std::net::{SocketAddr, TcpStream};
-use std::time::Duration;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use trace::Trace;
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -24,43 +40,160 @@ pub enum Error {
     BencodeDeserializeError {
         bencode_err: serde_bencode::error::Error,
     },
-    #[fail(display = "Bencode format error")]
+    #[fail(display = "bencode decode failed: {}", bc_err)]
+    BencodeError { bc_err: bencode::Error },
+    #[fail(display = "edn error: {}", edn_err)]
+    EdnError { edn_err: edn::Error },
+    #[fail(display = "Bencode format error: {}", _0)]
     BencodeFormatError(RespError),
     #[fail(display = "Nrepl returned unsuccessful status: {}", status)]
     ResponseStatusError { status: String },
+    #[fail(display = "unrepl daemon failed: {}", msg)]
+    DaemonError { msg: String },
+    #[fail(display = "nrepl refused connection at {}", addr)]
+    ConnectionRefused { addr: String },
+    #[fail(
+        display = "timed out connecting to nrepl at {} after {:?}",
+        addr, timeout
+    )]
+    ConnectTimeout { addr: String, timeout: Duration },
+    #[fail(display = "op `{}` wasn't done in {:?}", id, timeout)]
+    ReadTimeout { id: String, timeout: Duration },
+    #[fail(display = "nrepl sent nothing for op `{}` in {:?}", id, timeout)]
+    IdleTimeout { id: String, timeout: Duration },
+    #[fail(display = "message id `{}` is taken by op which isn't done yet", id)]
+    DuplicateId { id: String },
+    #[fail(display = "nrepl closed connection before op `{}` was done", id)]
+    ServerClosed { id: String },
+    #[fail(display = "nrepl sent malformed data, connection is dropped: {}", msg)]
+    BrokenStream { msg: String },
+    #[fail(display = "nrepl tls error: {}", tls_err)]
+    TlsError { tls_err: rustls::Error },
+    #[fail(display = "bad tls config: {}", msg)]
+    TlsConfigError { msg: String },
+    #[fail(display = "bad nrepl address: {}", addr)]
+    BadAddr { addr: String },
+    #[fail(display = "can't resolve {}: {}", host, ioerr)]
+    ResolveError { host: String, ioerr: std::io::Error },
+    #[fail(
+        display = "unknown nrepl transport: {}, expected bencode or edn",
+        codec
+    )]
+    UnknownCodec { codec: String },
 }
 
-#[derive(Debug)]
-pub enum Status {
-    Done(Vec<Resp>),
-    State(Vec<Resp>),
-    NoInfo(Vec<Resp>),
-    UnknownOp(String, Vec<Resp>),
-    EvalError(Vec<Resp>),
-    UnknownStatus(Vec<String>, Vec<Resp>),
-}
+/// Status flags which nrepl reported for an op, collected from all of its responses.
+///
+/// Flags come in arbitrary combinations, e.g. `["done", "error", "namespace-not-found"]`, or
+/// spread over several messages, like `["eval-error"]` followed by `["done"]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status(BTreeSet<String>);
 
 impl Status {
+    /// Reads `status` of single response, it's usually a list, but a string is accepted as well
+    pub fn of(resp: &Resp) -> Self {
+        let flags = match resp.get("status") {
+            Some(BencodeValue::List(vals)) => vals
+                .iter()
+                .filter_map(|v| bencode::try_into_string(v.clone()).ok())
+                .collect(),
+            Some(BencodeValue::Bytes(bs)) => String::from_utf8(bs.clone()).into_iter().collect(),
+            _ => BTreeSet::new(),
+        };
+
+        Self(flags)
+    }
+
+    pub fn extend(&mut self, other: Status) {
+        self.0.extend(other.0)
+    }
+
+    pub fn contains(&self, flag: &str) -> bool {
+        self.0.contains(flag)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Nothing else will be sent for the op
+    pub fn is_done(&self) -> bool {
+        self.contains("done")
+    }
+
+    /// The op itself has failed, more specific flags usually come along
+    pub fn is_error(&self) -> bool {
+        self.contains("error")
+    }
+
+    /// Evaluated code has thrown, the op itself could still succeed
+    pub fn is_eval_error(&self) -> bool {
+        self.contains("eval-error")
+    }
+
+    pub fn is_unknown_op(&self) -> bool {
+        self.contains("unknown-op")
+    }
+
+    pub fn is_unknown_session(&self) -> bool {
+        self.contains("unknown-session")
+    }
+
+    pub fn is_session_closed(&self) -> bool {
+        self.contains("session-closed")
+    }
+
+    pub fn is_namespace_not_found(&self) -> bool {
+        self.contains("namespace-not-found")
+    }
+
+    /// Evaluation waits for data on stdin
+    pub fn is_need_input(&self) -> bool {
+        self.contains("need-input")
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.contains("interrupted")
+    }
+
+    pub fn is_no_info(&self) -> bool {
+        self.contains("no-info")
+    }
+
+    /// Sent by cider.nrepl.middleware.track-state
+    pub fn is_state(&self) -> bool {
+        self.contains("state")
+    }
+
     pub fn name(&self) -> String {
-        match self {
-            Self::Done(_) => "done".to_string(),
-            Self::State(_) => "state (cider.nrepl.middleware.track-state)".to_string(),
-            Self::NoInfo(_) => "no-info".to_string(),
-            Self::EvalError(_) => "eval-error".to_string(),
-            Self::UnknownStatus(statuses, _) => statuses.join(","),
-            Self::UnknownOp(_op, _) => "Unknown Op".to_string(),
-        }
+        self.0.iter().cloned().collect::<Vec<String>>().join(",")
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// All responses to single op along with their combined status
+#[derive(Debug)]
+pub struct Responses {
+    status: Status,
+    resps: Vec<Resp>,
+}
+
+impl Responses {
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    pub fn resps(&self) -> &Vec<Resp> {
+        &self.resps
     }
 
     pub fn into_resps(self) -> Vec<Resp> {
-        match self {
-            Self::Done(resps) => resps,
-            Self::State(resps) => resps,
-            Self::NoInfo(resps) => resps,
-            Self::EvalError(resps) => resps,
-            Self::UnknownStatus(_, resps) => resps,
-            Self::UnknownOp(_, resps) => resps,
-        }
+        self.resps
     }
 }
 
@@ -76,21 +209,120 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<bencode::Error> for Error {
+    fn from(bc_err: bencode::Error) -> Self {
+        Self::BencodeError { bc_err }
+    }
+}
+
+impl From<edn::Error> for Error {
+    fn from(edn_err: edn::Error) -> Self {
+        Self::EdnError { edn_err }
+    }
+}
+
 impl From<RespError> for Error {
     fn from(err: RespError) -> Self {
         Self::BencodeFormatError(err)
     }
 }
 
+/// Message to nrepl, e.g. `Op::new("info").arg("ns", "user").arg("symbol", "map")`
 #[derive(Debug)]
 pub struct Op {
     name: String,
-    args: Vec<(String, String)>,
+    id: Option<String>,
+    /// Each key is there once, in the order it was first set
+    args: Vec<(String, BencodeValue)>,
 }
 
 impl Op {
-    pub fn new(name: String, args: Vec<(String, String)>) -> Op {
-        Op { name, args }
+    pub fn new(name: &str) -> Op {
+        Op {
+            name: name.to_string(),
+            id: None,
+            args: vec![],
+        }
+    }
+
+    pub fn arg(self, key: &str, val: &str) -> Op {
+        self.value(key, BencodeValue::Bytes(val.as_bytes().to_vec()))
+    }
+
+    /// Nothing is sent for `None`
+    pub fn opt_arg(self, key: &str, val: Option<&str>) -> Op {
+        match val {
+            Some(val) => self.arg(key, val),
+            None => self,
+        }
+    }
+
+    /// E.g. `line` and `column`
+    pub fn int(self, key: &str, val: i64) -> Op {
+        self.value(key, BencodeValue::Int(val))
+    }
+
+    /// List of strings, e.g. `exclude`
+    pub fn list<S: AsRef<str>>(self, key: &str, items: &[S]) -> Op {
+        let items = items
+            .iter()
+            .map(|s| BencodeValue::Bytes(s.as_ref().as_bytes().to_vec()))
+            .collect();
+
+        self.value(key, BencodeValue::List(items))
+    }
+
+    /// E.g. `nrepl.middleware.print/options`
+    pub fn dict(self, key: &str, entries: Vec<(&str, BencodeValue)>) -> Op {
+        let entries = entries
+            .into_iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v))
+            .collect();
+
+        self.value(key, BencodeValue::Dict(entries))
+    }
+
+    /// Argument of any type, replaces the one set before
+    pub fn value(mut self, key: &str, val: BencodeValue) -> Op {
+        self.set_value(key, val);
+        self
+    }
+
+    pub fn set_value(&mut self, key: &str, val: BencodeValue) {
+        match self.args.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = val,
+            None => self.args.push((key.to_string(), val)),
+        }
+    }
+
+    fn set_args(&mut self, args: &[(String, String)]) {
+        for (k, v) in args {
+            self.set_value(k, BencodeValue::Bytes(v.as_bytes().to_vec()));
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&BencodeValue> {
+        self.args.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Argument which is a UTF-8 string
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        match self.get(key) {
+            Some(BencodeValue::Bytes(bs)) => std::str::from_utf8(bs).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn set_id(&mut self, id: String) {
+        self.id = Some(id);
     }
 }
 
@@ -99,10 +331,15 @@ impl Serialize for Op {
     where
         S: Serializer,
     {
-        let mut state = s.serialize_map(Some(1 + self.args.len()))?;
+        let len = 1 + self.id.iter().count() + self.args.len();
+        let mut state = s.serialize_map(Some(len))?;
 
         state.serialize_entry("op", &self.name)?;
 
+        if let Some(id) = &self.id {
+            state.serialize_entry("id", id)?;
+        }
+
         for (k, v) in self.args.iter() {
             state.serialize_entry(k, v)?;
         }
@@ -111,9 +348,15 @@ impl Serialize for Op {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Resp(HashMap<String, BencodeValue>);
 
+impl From<HashMap<String, BencodeValue>> for Resp {
+    fn from(map: HashMap<String, BencodeValue>) -> Self {
+        Self(map)
+    }
+}
+
 #[derive(Debug)]
 pub enum RespError {
     ExpectedMap(BencodeValue),
@@ -153,6 +396,23 @@ impl fmt::Display for RespError {
     }
 }
 
+impl Resp {
+    /// Message id of the op this response belongs to
+    pub fn id(&self) -> Option<String> {
+        match self.get("id") {
+            Some(BencodeValue::Bytes(bs)) => String::from_utf8(bs.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    /// Reads fields into `T`, see `bencode::de` for what's accepted
+    pub fn decode<T: DeserializeOwned>(self) -> Result<T, bencode::de::Error> {
+        let fields = self.0.into_iter().map(|(k, v)| (k.into_bytes(), v));
+
+        bencode::de::from_value(BencodeValue::Dict(fields.collect()))
+    }
+}
+
 impl std::ops::Deref for Resp {
     type Target = HashMap<String, BencodeValue>;
 
@@ -175,7 +435,8 @@ impl TryFrom<BencodeValue> for Resp {
             BencodeValue::Dict(map) => {
                 let pairs = map
                     .into_iter()
-                    .map(|(k, v)| (String::from_utf8(k).unwrap(), TryFrom::try_from(v).unwrap()));
+                    .map(|(k, v)| Ok((String::from_utf8(k)?, v)))
+                    .collect::<Result<Vec<_>, RespError>>()?;
                 Ok(Self(HashMap::from_iter(pairs)))
             }
             v => Err(Self::Error::ExpectedMap(v)),
@@ -183,123 +444,986 @@ impl TryFrom<BencodeValue> for Resp {
     }
 }
 
+/// Copies every string out of the buffer the message was decoded in
+impl TryFrom<&bencode::ValueRef<'_>> for Resp {
+    type Error = RespError;
+
+    fn try_from(val: &bencode::ValueRef<'_>) -> Result<Self, Self::Error> {
+        match val {
+            bencode::ValueRef::Dict(entries) => {
+                let pairs = entries
+                    .iter()
+                    .map(|(k, v)| Ok((String::from_utf8(k.to_vec())?, v.to_value())))
+                    .collect::<Result<Vec<_>, RespError>>()?;
+                Ok(Self(HashMap::from_iter(pairs)))
+            }
+            v => Err(Self::Error::ExpectedMap(v.to_value())),
+        }
+    }
+}
+
 fn is_final_resp(resp: &Resp) -> bool {
-    resp.contains_key("status")
+    Status::of(resp).is_done()
 }
 
-fn get_status(resp: &Resp) -> Option<Vec<String>> {
-    if let Some(status) = resp.get("status") {
-        Some(bencode::try_into_str_vec(status.clone()).unwrap())
-    } else {
-        None
+fn parse_resps(resps: Vec<Resp>) -> Responses {
+    let mut status = Status::default();
+
+    for resp in resps.iter() {
+        status.extend(Status::of(resp));
     }
+
+    Responses { status, resps }
 }
 
-fn parse_resps(resps: Vec<Resp>) -> Result<Status, Error> {
-    for resp in resps.iter() {
-        if is_final_resp(&resp) {
-            let status = get_status(&resp).unwrap();
-
-            if status == ["done"] {
-                return Ok(Status::Done(resps));
-            } else if status == ["state"] {
-                return Ok(Status::State(resps));
-            } else if status == ["eval-error"] {
-                return Ok(Status::EvalError(resps));
-            } else if status == ["done", "no-info"] {
-                return Ok(Status::NoInfo(resps));
-            } else if status.iter().any(|s| s == "unknown-op") {
-                return Ok(Status::UnknownOp(status.join(","), resps));
-            } else {
-                return Ok(Status::UnknownStatus(status, resps));
+/// Limits on how long we wait for nrepl, `None` means no limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Duration,
+    /// Whole op, from sending it till the final response
+    pub read: Option<Duration>,
+    /// Between two responses of the op
+    pub idle: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(3),
+            read: None,
+            idle: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+static NEXT_MSG_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Generates message id which is unique within this process
+fn next_msg_id() -> String {
+    format!(
+        "unrepl-{}-{}",
+        std::process::id(),
+        NEXT_MSG_ID.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+/// Fails with `UnexpectedEof` io error when the stream has ended
+pub(crate) fn read_resp<R: BufRead>(r: &mut R) -> Result<Resp, Error> {
+    match bencode::read_value(r)? {
+        Some(val) => Ok(TryFrom::try_from(val)?),
+        None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+    }
+}
+
+/// Wire format of nrepl messages, `Resp` looks the same for all of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Default transport of nrepl
+    Bencode,
+    /// `nrepl.transport/edn`
+    Edn,
+}
+
+impl Codec {
+    fn encode(&self, op: &Op) -> Result<Vec<u8>, Error> {
+        let bencode = serde_bencode::to_bytes(op)?;
+
+        match self {
+            Codec::Bencode => Ok(bencode),
+            Codec::Edn => {
+                let val: BencodeValue = serde_bencode::from_bytes(&bencode)?;
+                Ok(edn::to_string(&val).into_bytes())
             }
         }
     }
-    unreachable!()
+
+    fn decode<R: BufRead>(&self, r: &mut R) -> Result<Resp, Error> {
+        match self {
+            Codec::Bencode => read_resp(r),
+            Codec::Edn => match edn::read(r)? {
+                Some(val) => Ok(TryFrom::try_from(val)?),
+                None => Err(RespError::ExpectedMap(BencodeValue::Bytes(b"nil".to_vec())).into()),
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Bencode => "bencode",
+            Codec::Edn => "edn",
+        }
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bencode" => Ok(Codec::Bencode),
+            "edn" => Ok(Codec::Edn),
+            _ => Err(Error::UnknownCodec {
+                codec: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// Byte stream which carries nrepl messages
+pub(crate) trait Socket: Read + Write + Send {
+    fn try_clone_socket(&self) -> std::io::Result<Box<dyn Socket>>;
+
+    fn shutdown_socket(&self) -> std::io::Result<()>;
+
+    fn set_socket_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl Socket for TcpStream {
+    fn try_clone_socket(&self) -> std::io::Result<Box<dyn Socket>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown_socket(&self) -> std::io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn set_socket_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+impl Socket for UnixStream {
+    fn try_clone_socket(&self) -> std::io::Result<Box<dyn Socket>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn shutdown_socket(&self) -> std::io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    fn set_socket_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+fn tcp_connect_stream(addr: &SocketAddr, timeout: Duration) -> Result<TcpStream, Error> {
+    let tcp = TcpStream::connect_timeout(addr, timeout).map_err(|e| match e.kind() {
+        std::io::ErrorKind::ConnectionRefused => Error::ConnectionRefused {
+            addr: addr.to_string(),
+        },
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => Error::ConnectTimeout {
+            addr: addr.to_string(),
+            timeout,
+        },
+        _ => e.into(),
+    })?;
+    tcp.set_nonblocking(false)?;
+    Ok(tcp)
+}
+
+fn tcp_connect(addr: &SocketAddr, timeout: Duration) -> Result<Box<dyn Socket>, Error> {
+    Ok(Box::new(tcp_connect_stream(addr, timeout)?))
+}
+
+fn unix_connect(path: &Path) -> Result<Box<dyn Socket>, Error> {
+    let unix = UnixStream::connect(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::ConnectionRefused => Error::ConnectionRefused {
+            addr: path.display().to_string(),
+        },
+        _ => e.into(),
+    })?;
+    Ok(Box::new(unix))
+}
+
+/// Where nrepl is listening.
+///
+/// Its string form is used as a key for sessions, unix socket paths are expected to be absolute
+/// so they can't be mistaken for anything else. Host names are resolved beforehand, so the same
+/// nrepl gets the same key however it was named.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    /// `nrepl --socket path`, available since nREPL 0.9
+    Unix(PathBuf),
+    /// Written as `tls://ip:port`, or `tls://host@ip:port` when nrepl's certificate is checked
+    /// against the host name it was resolved from
+    Tls {
+        addr: SocketAddr,
+        server_name: Option<String>,
+    },
+}
+
+impl Addr {
+    pub(crate) fn connect(
+        &self,
+        timeout: Duration,
+        tls: &TlsConfig,
+    ) -> Result<Box<dyn Socket>, Error> {
+        match self {
+            Addr::Tcp(addr) => tcp_connect(addr, timeout),
+            Addr::Unix(path) => unix_connect(path),
+            Addr::Tls { addr, server_name } => {
+                tls::tls_connect(addr, server_name.as_deref(), tls, timeout)
+            }
+        }
+    }
+
+    /// Parses address given by user: `port`, `host:port`, `nrepl://host:port` or
+    /// `tls://host:port`, host name is resolved. Bare port is looked for on `default_host`.
+    pub fn resolve(addr: &str, default_host: &str) -> Result<Addr, Error> {
+        let bad_addr = || Error::BadAddr {
+            addr: addr.to_string(),
+        };
+
+        let (is_tls, host_port) = if let Some(rest) = addr.strip_prefix("tls://") {
+            (true, rest)
+        } else {
+            (false, addr.strip_prefix("nrepl://").unwrap_or(addr))
+        };
+        let host_port = host_port.trim_end_matches('/');
+
+        let (host, port) = match host_port.parse::<u16>() {
+            Ok(port) => (default_host, port),
+            Err(_) => {
+                let (host, port) = host_port.rsplit_once(':').ok_or_else(bad_addr)?;
+                (host, port.parse::<u16>().map_err(|_| bad_addr())?)
+            }
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let socket_addr = (host, port)
+            .to_socket_addrs()
+            .map_err(|ioerr| Error::ResolveError {
+                host: host.to_string(),
+                ioerr,
+            })?
+            .next()
+            .ok_or_else(bad_addr)?;
+
+        Ok(if is_tls {
+            Addr::Tls {
+                addr: socket_addr,
+                server_name: host.parse::<IpAddr>().is_err().then(|| host.to_string()),
+            }
+        } else {
+            Addr::Tcp(socket_addr)
+        })
+    }
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Self {
+        Addr::Tcp(addr)
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::Tcp(addr) => write!(f, "{}", addr),
+            Addr::Unix(path) => write!(f, "{}", path.display()),
+            Addr::Tls {
+                addr,
+                server_name: None,
+            } => write!(f, "tls://{}", addr),
+            Addr::Tls {
+                addr,
+                server_name: Some(name),
+            } => write!(f, "tls://{}@{}", name, addr),
+        }
+    }
+}
+
+impl std::str::FromStr for Addr {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            Ok(Addr::Unix(PathBuf::from(s)))
+        } else if let Some(addr) = s.strip_prefix("tls://") {
+            Ok(match addr.split_once('@') {
+                Some((name, addr)) => Addr::Tls {
+                    addr: addr.parse()?,
+                    server_name: Some(name.to_string()),
+                },
+                None => Addr::Tls {
+                    addr: addr.parse()?,
+                    server_name: None,
+                },
+            })
+        } else {
+            Ok(Addr::Tcp(s.parse()?))
+        }
+    }
+}
+
+/// Op gets an error instead of a response when nrepl's message for it couldn't be decoded
+type PendingMap = Arc<Mutex<HashMap<String, Sender<Result<Resp, Error>>>>>;
+
+/// Single socket to nrepl which can carry any number of ops at once.
+///
+/// Every op is tagged with an unique `id`, and a reader thread routes incoming responses to the
+/// op they belong to.
+#[derive(Clone)]
+struct Connection {
+    inner: Arc<ConnectionInner>,
+}
+
+struct ConnectionInner {
+    socket: Mutex<Box<dyn Socket>>,
+    codec: Codec,
+    pending: PendingMap,
+    closed: Arc<AtomicBool>,
+    trace: Option<Trace>,
+}
+
+impl Drop for ConnectionInner {
+    fn drop(&mut self) {
+        // Unblocks the reader thread
+        if let Ok(socket) = self.socket.get_mut() {
+            let _ = socket.shutdown_socket();
+        }
+    }
+}
+
+impl Connection {
+    fn new(
+        socket: Box<dyn Socket>,
+        codec: Codec,
+        trace: Option<Trace>,
+    ) -> Result<Connection, Error> {
+        let reader = socket.try_clone_socket()?;
+        let pending: PendingMap = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        {
+            let pending = pending.clone();
+            let closed = closed.clone();
+            let trace = trace.clone();
+            thread::spawn(move || read_loop(reader, codec, pending, closed, trace));
+        }
+
+        Ok(Connection {
+            inner: Arc::new(ConnectionInner {
+                socket: Mutex::new(socket),
+                codec,
+                pending,
+                closed,
+                trace,
+            }),
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::SeqCst)
+    }
+
+    fn send(&self, mut op: Op, timeouts: Timeouts) -> Result<PendingOp, Error> {
+        let id = op.id.clone().unwrap_or_else(next_msg_id);
+        op.set_id(id.clone());
+
+        let msg = self.inner.codec.encode(&op)?;
+        let (tx, rx) = channel();
+
+        // Registering before writing, so we can't miss a fast response. Responses to ops with
+        // the same id couldn't be told apart.
+        match self.inner.pending.lock().unwrap().entry(id.clone()) {
+            Entry::Occupied(_) => return Err(Error::DuplicateId { id }),
+            Entry::Vacant(entry) => entry.insert(tx),
+        };
+
+        let pending = PendingOp {
+            id,
+            rx,
+            conn: self.clone(),
+            timeouts,
+            started: Instant::now(),
+            finished: false,
+            session: op.get_str("session").map(str::to_string),
+            relay_args: vec![],
+            relay: None,
+            stdin: None,
+        };
+
+        if let Some(trace) = &self.inner.trace {
+            trace.sent(&op);
+        }
+
+        self.inner.socket.lock().unwrap().write_all(&msg)?;
+
+        Ok(pending)
+    }
+}
+
+/// Size of a single read from nrepl socket, messages can span any number of them
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Passes response, or the error of a message which isn't a valid one, to op with `id`.
+/// It's built only when there's such op or the trace, others are dropped as they are.
+fn dispatch<F>(id: Option<String>, build: F, pending: &PendingMap, trace: &Option<Trace>)
+where
+    F: FnOnce() -> Result<Resp, Error>,
+{
+    let is_awaited = match &id {
+        Some(id) => pending.lock().unwrap().contains_key(id),
+        None => false,
+    };
+    if !is_awaited && trace.is_none() {
+        return;
+    }
+
+    let res = build();
+    if let (Ok(resp), Some(trace)) = (&res, trace) {
+        trace.received(resp);
+    }
+
+    let id = match id {
+        Some(id) => id,
+        // Nobody could be waiting for it
+        None => return,
+    };
+
+    let mut pending = pending.lock().unwrap();
+    let is_final = matches!(&res, Ok(resp) if is_final_resp(resp));
+
+    if let Some(tx) = pending.get(&id) {
+        let _ = tx.send(res);
+    }
+
+    if is_final {
+        pending.remove(&id);
+    }
+}
+
+/// `id` of a message, even of one which isn't a valid response
+fn msg_id(val: &bencode::ValueRef<'_>) -> Option<String> {
+    match val.get("id") {
+        Some(bencode::ValueRef::Bytes(bs)) => std::str::from_utf8(bs).ok().map(str::to_string),
+        _ => None,
+    }
+}
+
+/// Responses are built right from the decoder's buffer, which is kept between reads, so
+/// messages can be split between reads in any way. Returns when connection is closed, fails
+/// when nrepl sends something which isn't bencode at all.
+fn read_bencode(
+    mut socket: Box<dyn Socket>,
+    pending: &PendingMap,
+    trace: &Option<Trace>,
+) -> Result<(), Error> {
+    let mut decoder = bencode::Decoder::new();
+    let mut chunk = vec![0; READ_CHUNK];
+
+    loop {
+        loop {
+            match decoder.next_ref() {
+                Ok(Some(val)) => {
+                    let build = || Resp::try_from(&val).map_err(Error::from);
+                    dispatch(msg_id(&val), build, pending, trace);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    if decoder.is_broken() {
+                        return Err(e.into());
+                    }
+                    // Malformed message is skipped, there's no telling which op it was for
+                }
+            }
+        }
+
+        let n = match socket.read(&mut chunk) {
+            Ok(n) if n > 0 => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            _ => return Ok(()),
+        };
+        decoder.feed(&chunk[..n]);
+    }
+}
+
+/// Same as `read_bencode`, for `nrepl.transport/edn`
+fn read_edn(
+    socket: Box<dyn Socket>,
+    pending: &PendingMap,
+    trace: &Option<Trace>,
+) -> Result<(), Error> {
+    let mut r = BufReader::new(socket);
+
+    loop {
+        let val = match edn::read(&mut r) {
+            Ok(val) => val,
+            Err(edn::Error::IOError { .. }) | Err(edn::Error::UnexpectedEof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Only maps are responses, there's no telling which op anything else was for
+        if let Some(Ok(resp)) = val.map(Resp::try_from) {
+            dispatch(resp.id(), || Ok(resp), pending, trace);
+        }
+    }
+}
+
+fn read_loop(
+    socket: Box<dyn Socket>,
+    codec: Codec,
+    pending: PendingMap,
+    closed: Arc<AtomicBool>,
+    trace: Option<Trace>,
+) {
+    let res = match codec {
+        Codec::Bencode => read_bencode(socket, &pending, &trace),
+        Codec::Edn => read_edn(socket, &pending, &trace),
+    };
+
+    closed.store(true, Ordering::SeqCst);
+
+    let mut pending = pending.lock().unwrap();
+    // Ops still waiting for responses learn why there won't be any
+    if let Err(e) = res {
+        let msg = e.to_string();
+
+        for tx in pending.values() {
+            let _ = tx.send(Err(Error::BrokenStream { msg: msg.clone() }));
+        }
+    }
+    // Dropping senders wakes up everyone who still waits for responses
+    pending.clear();
+}
+
+/// Op which was already sent to nrepl.
+///
+/// Iterating over it yields responses as soon as they arrive, the final response is the last
+/// item. `wait` collects all of them at once.
+pub struct PendingOp {
+    id: String,
+    rx: Receiver<Result<Resp, Error>>,
+    conn: Connection,
+    timeouts: Timeouts,
+    started: Instant,
+    finished: bool,
+    /// Session the op was sent to
+    session: Option<String>,
+    /// Go along with every op sent through the relay
+    relay_args: Vec<(String, String)>,
+    relay: Option<Arc<dyn Relay>>,
+    stdin: Option<Input>,
+}
+
+/// Where evaluation which waits for input gets it from
+type Input = Arc<Mutex<Box<dyn BufRead + Send>>>;
+
+/// Stops evaluation of `PendingOp` from another thread, e.g. on Ctrl-C
+pub struct Interrupter {
+    id: String,
+    session: String,
+    conn: Connection,
+    relay_args: Vec<(String, String)>,
+    timeouts: Timeouts,
+}
+
+impl Interrupter {
+    /// Asks nrepl to interrupt the op, which gets `interrupted` status then
+    pub fn interrupt(&self) -> Result<(), Error> {
+        let mut op = Op::new("interrupt")
+            .arg("session", &self.session)
+            .arg("interrupt-id", &self.id);
+        op.set_args(&self.relay_args);
+
+        // Outcome is seen in responses to the interrupted op
+        self.conn.send(op, self.timeouts)?;
+
+        Ok(())
+    }
+}
+
+impl PendingOp {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Only ops sent to a session can be interrupted
+    pub fn interrupter(&self) -> Option<Interrupter> {
+        Some(Interrupter {
+            id: self.id.clone(),
+            session: self.session.clone()?,
+            conn: self.conn.clone(),
+            relay_args: self.relay_args.clone(),
+            timeouts: self.timeouts,
+        })
+    }
+
+    /// Answers `need-input` with a line from `stdin`
+    fn forward_stdin(&self, resp: &Resp) -> Result<(), Error> {
+        let (stdin, session) = match (&self.stdin, resp.get("session")) {
+            (Some(stdin), Some(session)) => (
+                stdin,
+                bencode::try_into_string(session.clone())
+                    .map_err(|_| RespError::ExpectedString(session.clone()))?,
+            ),
+            _ => return Ok(()),
+        };
+
+        // Empty string means end of input for nrepl
+        let mut line = String::new();
+        stdin.lock().unwrap().read_line(&mut line)?;
+
+        let mut op = Op::new("stdin")
+            .arg("stdin", &line)
+            .arg("session", &session);
+        op.set_args(&self.relay_args);
+
+        // Nothing interesting in its response
+        self.conn.send(op, self.timeouts)?;
+
+        Ok(())
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    fn recv(&self) -> Result<Resp, Error> {
+        let closed = || Error::ServerClosed {
+            id: self.id.clone(),
+        };
+        let read_left = self
+            .timeouts
+            .read
+            .map(|read| read.checked_sub(self.started.elapsed()).unwrap_or_default());
+
+        // Whichever limit comes first
+        let (wait, timeout_err) = match (read_left, self.timeouts.idle) {
+            (Some(left), Some(idle)) if idle < left => (
+                Some(idle),
+                Error::IdleTimeout {
+                    id: self.id.clone(),
+                    timeout: idle,
+                },
+            ),
+            (Some(left), _) => (
+                Some(left),
+                Error::ReadTimeout {
+                    id: self.id.clone(),
+                    timeout: self.timeouts.read.unwrap(),
+                },
+            ),
+            (None, idle) => (
+                idle,
+                Error::IdleTimeout {
+                    id: self.id.clone(),
+                    timeout: idle.unwrap_or_default(),
+                },
+            ),
+        };
+
+        match wait {
+            Some(wait) => self.rx.recv_timeout(wait).map_err(|e| match e {
+                RecvTimeoutError::Timeout => timeout_err,
+                RecvTimeoutError::Disconnected => closed(),
+            })?,
+            None => self.rx.recv().map_err(|_| closed())?,
+        }
+    }
+
+    /// Blocks until the final response for this op arrives
+    pub fn wait(self) -> Result<Responses, Error> {
+        let resps = self.collect::<Result<Vec<Resp>, Error>>()?;
+
+        Ok(parse_resps(resps))
+    }
+}
+
+impl Iterator for PendingOp {
+    type Item = Result<Resp, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let res = self.recv().and_then(|resp| {
+            if Status::of(&resp).is_need_input() {
+                self.forward_stdin(&resp)?;
+            }
+            if is_final_resp(&resp) {
+                self.finished = true;
+                if let Some(relay) = &self.relay {
+                    relay.check_resp(&resp)?;
+                }
+            }
+            Ok(resp)
+        });
+
+        if res.is_err() {
+            self.finished = true;
+        }
+
+        Some(res)
+    }
+}
+
+impl Drop for PendingOp {
+    fn drop(&mut self) {
+        self.conn.inner.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+enum Mode {
+    PerOp,
+    Persistent(Mutex<Option<Connection>>),
+}
+
+/// Process ops are sent to instead of nrepl, it forwards them on, e.g. `unrepl daemon`
+pub trait Relay: Send + Sync {
+    /// Unix socket the relay listens on, it talks bencode whatever nrepl does
+    fn socket(&self) -> &Path;
+
+    /// Go along with every op, e.g. to tell where it should be forwarded
+    fn op_args(&self, nrepl: &NreplStream) -> Vec<(String, String)>;
+
+    /// Final response of an op may carry the relay's own failure
+    fn check_resp(&self, resp: &Resp) -> Result<(), Error>;
+
+    /// Sessions are kept track of by the relay, not the CLI
+    fn session(&self, nrepl: &NreplStream) -> Result<Session, failure::Error>;
 }
 
 /// It is responsible for communication with nrepl bencode socket
 ///
 /// 2020-03-24 Decided to open tcp stream for each OP because it proved to work more reliable
 /// But for sure there are some problems on "nrepl" side
-
+///
+/// `NreplStream::persistent` keeps a single connection instead, ops are correlated with their
+/// responses by message `id`, so several of them can be in flight at once.
+///
+/// `NreplStream::via_relay` sends ops to a `Relay`, which forwards them to nrepl
 pub struct NreplStream {
-    // tcp: TcpStream,
-    socket_addr: SocketAddr,
+    addr: Addr,
+    relay: Option<Arc<dyn Relay>>,
+    mode: Mode,
+    timeouts: Timeouts,
+    tls: TlsConfig,
+    /// `None` until it's detected
+    codec: Mutex<Option<Codec>>,
+    input: Option<Input>,
+    trace: Option<Trace>,
 }
 
 impl NreplStream {
-    // pub fn connect_timeout(addr: &SocketAddr) -> Result<NreplStream, Error> {
-    //     let conn = TcpStream::connect_timeout(addr, Duration::new(3, 0))
-    //         .and_then(|t| {
-    //             t.set_nonblocking(false)?;
-    //             t.set_read_timeout(Some(Duration::new(5, 0)))?;
-    //             Ok(t)
-    //         })
-    //         .map(|s| NreplStream {
-    //             tcp: s,
-    //             socket_addr: addr.clone(),
-    //         })?;
-    //     Ok(conn)
-    // }
-
-    pub fn new(addr: &SocketAddr) -> Result<NreplStream, Error> {
+    /// Opens new connection for each op
+    pub fn new(addr: &Addr) -> Result<NreplStream, Error> {
         Ok(NreplStream {
-            socket_addr: addr.clone()
+            addr: addr.clone(),
+            relay: None,
+            mode: Mode::PerOp,
+            timeouts: Timeouts::default(),
+            tls: TlsConfig::default(),
+            codec: Mutex::new(Some(Codec::Bencode)),
+            input: None,
+            trace: None,
         })
     }
 
-    fn socket_timeout(&self) -> Result<TcpStream, Error> {
-        TcpStream::connect_timeout(&self.socket_addr, Duration::new(3, 0))
-            .and_then(|t| {
-                t.set_nonblocking(false)?;
-                t.set_read_timeout(Some(Duration::new(5, 0)))?;
-                Ok(t)
-            })
-            .map_err(|e| e.into())
+    /// Shares single long-lived connection between all ops. It is established on first op
+    /// and re-established if nrepl drops it.
+    pub fn persistent(addr: &Addr) -> Result<NreplStream, Error> {
+        Ok(NreplStream {
+            addr: addr.clone(),
+            relay: None,
+            mode: Mode::Persistent(Mutex::new(None)),
+            timeouts: Timeouts::default(),
+            tls: TlsConfig::default(),
+            codec: Mutex::new(Some(Codec::Bencode)),
+            input: None,
+            trace: None,
+        })
     }
 
-    fn send_op<T: Into<Op>>(&self, tcp: &TcpStream, op: T) -> Result<(), Error> {
-        let mut bw = BufWriter::new(tcp);
-        let bencode = serde_bencode::to_bytes(&op.into())?;
-        bw.write(&bencode)?;
-        Ok(())
+    /// Talks to nrepl at `addr` through `relay`
+    pub fn via_relay(addr: &Addr, relay: Arc<dyn Relay>) -> Result<NreplStream, Error> {
+        Ok(NreplStream {
+            addr: addr.clone(),
+            relay: Some(relay),
+            mode: Mode::Persistent(Mutex::new(None)),
+            timeouts: Timeouts::default(),
+            tls: TlsConfig::default(),
+            codec: Mutex::new(Some(Codec::Bencode)),
+            input: None,
+            trace: None,
+        })
     }
 
-    fn read_resp(&self, tcp: &TcpStream) -> Result<Resp, Error> {
-        let mut r = BufReader::new(tcp);
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
 
-        let mut deser = serde_bencode::de::Deserializer::new(&mut r);
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
 
-        let val: BencodeValue = serde::Deserialize::deserialize(&mut deser)?;
+    /// Certificates for `Addr::Tls`
+    pub fn set_tls(&mut self, tls: TlsConfig) {
+        self.tls = tls;
+    }
 
-        Ok(TryFrom::try_from(val)?)
+    /// Wire format nrepl talks, `None` to detect it on connection
+    pub fn set_codec(&mut self, codec: Option<Codec>) {
+        self.codec = Mutex::new(codec);
     }
 
-    /// Serializes given `op` and sends to Nrepl socket using given transport
-    pub fn op<T: Into<Op>>(&self, op: T) -> Result<Status, Error> {
-        let mut resps: Vec<Resp> = vec![];
+    pub fn codec(&self) -> Option<Codec> {
+        *self.codec.lock().unwrap()
+    }
 
-        let tcp = self.socket_timeout()?;
+    /// Lines for evaluations which ask for input, without it they wait until timeout
+    pub fn set_input(&mut self, input: Box<dyn BufRead + Send>) {
+        self.input = Some(Arc::new(Mutex::new(input)));
+    }
 
-        self.send_op(&tcp, op.into())?;
+    /// Logs every message of the stream to `trace`
+    pub fn set_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
 
-        loop {
-            let resp = self.read_resp(&tcp)?;
-            let is_final = is_final_resp(&resp);
+    pub fn relay(&self) -> Option<&dyn Relay> {
+        self.relay.as_deref()
+    }
 
-            resps.push(resp);
+    pub fn is_relayed(&self) -> bool {
+        self.relay.is_some()
+    }
+
+    /// Asks nrepl to `describe` itself in EDN, bencode nrepl can't read it and hangs up
+    fn detect_codec(&self) -> Result<(Box<dyn Socket>, Codec), Error> {
+        let connect = || self.addr.connect(self.timeouts.connect, &self.tls);
+        let mut socket = connect()?;
+
+        let mut probe = Op::new("describe");
+        probe.set_id(next_msg_id());
+        socket.write_all(&Codec::Edn.encode(&probe)?)?;
+
+        socket.set_socket_read_timeout(Some(self.timeouts.connect))?;
+        let mut r = BufReader::new(socket.try_clone_socket()?);
+        let is_edn = matches!(r.fill_buf(), Ok(buf) if buf.first() == Some(&b'{'));
 
-            if is_final {
-                break;
+        if is_edn {
+            Codec::Edn.decode(&mut r)?;
+            socket.set_socket_read_timeout(None)?;
+            Ok((socket, Codec::Edn))
+        } else {
+            Ok((connect()?, Codec::Bencode))
+        }
+    }
+
+    fn open_connection(&self) -> Result<Connection, Error> {
+        if let Some(relay) = &self.relay {
+            let path = relay.socket();
+            let trace = self
+                .trace
+                .as_ref()
+                .map(|t| t.to_peer(path.display().to_string()));
+            return Connection::new(Box::new(UnixStream::connect(path)?), Codec::Bencode, trace);
+        }
+
+        let mut codec = self.codec.lock().unwrap();
+
+        let (socket, detected) = match *codec {
+            Some(c) => (self.addr.connect(self.timeouts.connect, &self.tls)?, c),
+            None => self.detect_codec()?,
+        };
+        *codec = Some(detected);
+
+        let trace = self
+            .trace
+            .as_ref()
+            .map(|t| t.to_peer(self.addr.to_string()));
+        Connection::new(socket, detected, trace)
+    }
+
+    fn connection(&self) -> Result<Connection, Error> {
+        match &self.mode {
+            Mode::PerOp => self.open_connection(),
+            Mode::Persistent(conn) => {
+                let mut conn = conn.lock().unwrap();
+
+                match &*conn {
+                    Some(c) if !c.is_closed() => Ok(c.clone()),
+                    _ => {
+                        let c = self.open_connection()?;
+                        *conn = Some(c.clone());
+                        Ok(c)
+                    }
+                }
             }
         }
+    }
+
+    /// Sends `op` without waiting for responses, returned `PendingOp` streams them
+    pub fn send<T: Into<Op>>(&self, op: T) -> Result<PendingOp, Error> {
+        self.send_with_timeouts(op, self.timeouts)
+    }
+
+    pub fn send_with_timeouts<T: Into<Op>>(
+        &self,
+        op: T,
+        timeouts: Timeouts,
+    ) -> Result<PendingOp, Error> {
+        let mut op = op.into();
+        op.set_args(&self.relay_args());
+
+        let mut pending = self.connection()?.send(op, timeouts)?;
+
+        pending.relay_args = self.relay_args();
+        pending.relay = self.relay.clone();
+        pending.stdin = self.input.clone();
+
+        Ok(pending)
+    }
+
+    fn relay_args(&self) -> Vec<(String, String)> {
+        match &self.relay {
+            Some(relay) => relay.op_args(self),
+            None => vec![],
+        }
+    }
+
+    /// Serializes given `op` and sends to Nrepl socket using given transport
+    pub fn op<T: Into<Op>>(&self, op: T) -> Result<Responses, Error> {
+        self.send(op)?.wait()
+    }
 
-        parse_resps(resps)
+    /// Sends typed op, using timeouts it asks for
+    pub fn typed_op<O, T>(&self, op: &O) -> Result<Responses, Error>
+    where
+        O: NreplOp<T>,
+        for<'a> &'a O: Into<Op>,
+    {
+        self.send_with_timeouts(op, op.timeouts(self.timeouts))?
+            .wait()
+    }
+
+    /// Same as `op`, but `on_resp` sees every response as soon as it arrives
+    pub fn op_each<T, F>(&self, op: T, mut on_resp: F) -> Result<Responses, Error>
+    where
+        T: Into<Op>,
+        F: FnMut(&Resp),
+    {
+        let mut resps: Vec<Resp> = vec![];
+
+        for resp in self.send(op)? {
+            let resp = resp?;
+            on_resp(&resp);
+            resps.push(resp);
+        }
+
+        Ok(parse_resps(resps))
+    }
+
+    pub fn addr(&self) -> &Addr {
+        &self.addr
     }
 
     pub fn addr_string(&self) -> String {
-        self.socket_addr.to_string()
+        self.addr.to_string()
     }
 }
 
@@ -307,6 +1431,11 @@ pub trait NreplOp<T> {
     type Error;
 
     fn send(&self, nrepl: &NreplStream) -> Result<T, Self::Error>;
+
+    /// Lets op override timeouts configured for the stream
+    fn timeouts(&self, stream_timeouts: Timeouts) -> Timeouts {
+        stream_timeouts
+    }
 }
 
 /// Check if there's already a port file written by `lein repl` for example
@@ -327,18 +1456,403 @@ mod tests {
     use serde_bencode::value::Value as BencodeValue;
     use std::collections::HashMap;
     use std::iter::FromIterator;
+    use std::net::TcpListener;
 
     #[test]
     fn final_resp_test() {
-        let final_resp = Resp(HashMap::from_iter(
-            vec![("status".to_string(), BencodeValue::Bytes(vec![]))].into_iter(),
-        ));
+        let final_resp = Resp(HashMap::from_iter(vec![(
+            "status".to_string(),
+            BencodeValue::List(vec![BencodeValue::Bytes(b"done".to_vec())]),
+        )]));
+
+        let not_final_resp = Resp(HashMap::from_iter(vec![(
+            "foo".to_string(),
+            BencodeValue::Bytes(vec![]),
+        )]));
 
-        let not_final_resp = Resp(HashMap::from_iter(
-            vec![("foo".to_string(), BencodeValue::Bytes(vec![]))].into_iter(),
-        ));
+        let eval_error_resp = Resp(HashMap::from_iter(vec![(
+            "status".to_string(),
+            BencodeValue::List(vec![BencodeValue::Bytes(b"eval-error".to_vec())]),
+        )]));
 
         assert!(is_final_resp(&final_resp));
         assert!(!is_final_resp(&not_final_resp));
+        assert!(!is_final_resp(&eval_error_resp));
+    }
+
+    fn status_resp(flags: &[&str]) -> Resp {
+        let flags = flags
+            .iter()
+            .map(|f| BencodeValue::Bytes(f.as_bytes().to_vec()))
+            .collect();
+
+        Resp(HashMap::from_iter(vec![(
+            "status".to_string(),
+            BencodeValue::List(flags),
+        )]))
+    }
+
+    #[test]
+    fn status_flags_are_combined_test() {
+        let res = parse_resps(vec![status_resp(&["eval-error"]), status_resp(&["done"])]);
+
+        assert!(res.status().is_done());
+        assert!(res.status().is_eval_error());
+        assert!(!res.status().is_error());
+
+        let res = parse_resps(vec![status_resp(&["done", "error", "namespace-not-found"])]);
+
+        assert!(res.status().is_error());
+        assert!(res.status().is_namespace_not_found());
+        assert_eq!(res.status().name(), "done,error,namespace-not-found");
+    }
+
+    #[test]
+    fn op_args_keep_their_types_test() {
+        let mut op = Op::new("info")
+            .arg("symbol", "map")
+            .int("line", 10)
+            .list("exclude", &["clojure.core"])
+            .dict(
+                "nrepl.middleware.print/options",
+                vec![("length", BencodeValue::Int(5))],
+            )
+            .arg("symbol", "filter");
+        op.set_id("1".to_string());
+
+        assert_eq!(op.get_str("symbol"), Some("filter"));
+        assert_eq!(op.get_str("line"), None);
+        // Bencode dicts are sorted by key
+        assert_eq!(
+            String::from_utf8(serde_bencode::to_bytes(&op).unwrap()).unwrap(),
+            "d7:excludel12:clojure.coree2:id1:14:linei10e\
+             30:nrepl.middleware.print/optionsd6:lengthi5ee2:op4:info6:symbol6:filtere"
+        );
+    }
+
+    #[test]
+    fn persistent_stream_routes_resps_by_id_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Answers to both ops in reverse order, over the same connection
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(tcp.try_clone().unwrap());
+            let mut w = tcp;
+            let first = read_resp(&mut r).unwrap().id().unwrap();
+            let second = read_resp(&mut r).unwrap().id().unwrap();
+
+            for id in [second, first] {
+                let mut resp: HashMap<&str, BencodeValue> = HashMap::new();
+                resp.insert("id", BencodeValue::Bytes(id.clone().into_bytes()));
+                resp.insert("value", BencodeValue::Bytes(id.into_bytes()));
+                resp.insert(
+                    "status",
+                    BencodeValue::List(vec![BencodeValue::Bytes(b"done".to_vec())]),
+                );
+                w.write_all(&serde_bencode::to_bytes(&resp).unwrap())
+                    .unwrap();
+            }
+        });
+
+        let n = NreplStream::persistent(&addr.into()).unwrap();
+        let first = n.send(Op::new("eval")).unwrap();
+        let second = n.send(Op::new("eval")).unwrap();
+
+        for pending in [first, second] {
+            let id = pending.id().to_string();
+
+            let res = pending.wait().unwrap();
+            assert!(res.status().is_done());
+
+            let value = res.into_resps().pop().unwrap().remove("value").unwrap();
+            assert_eq!(bencode::try_into_string(value).unwrap(), id);
+        }
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pending_op_yields_resps_before_final_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (proceed_tx, proceed_rx) = channel::<()>();
+
+        // Final response is sent only after client has seen the first one
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(tcp.try_clone().unwrap());
+            let mut w = tcp;
+            let id = read_resp(&mut r).unwrap().id().unwrap();
+
+            let mut out: HashMap<&str, BencodeValue> = HashMap::new();
+            out.insert("id", BencodeValue::Bytes(id.clone().into_bytes()));
+            out.insert("out", BencodeValue::Bytes(b"tick".to_vec()));
+            w.write_all(&serde_bencode::to_bytes(&out).unwrap())
+                .unwrap();
+
+            proceed_rx.recv().unwrap();
+
+            let mut done: HashMap<&str, BencodeValue> = HashMap::new();
+            done.insert("id", BencodeValue::Bytes(id.into_bytes()));
+            done.insert(
+                "status",
+                BencodeValue::List(vec![BencodeValue::Bytes(b"done".to_vec())]),
+            );
+            w.write_all(&serde_bencode::to_bytes(&done).unwrap())
+                .unwrap();
+        });
+
+        let n = NreplStream::new(&addr.into()).unwrap();
+        let mut pending = n.send(Op::new("eval")).unwrap();
+
+        let out = pending.next().unwrap().unwrap();
+        assert!(out.contains_key("out"));
+
+        proceed_tx.send(()).unwrap();
+
+        let done = pending.next().unwrap().unwrap();
+        assert!(is_final_resp(&done));
+        assert!(pending.next().is_none());
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pending_op_timeout_errors_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (close_tx, close_rx) = channel::<()>();
+
+        // Never answers, and closes connection when asked to
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            close_rx.recv().unwrap();
+            tcp.shutdown(Shutdown::Both).unwrap();
+        });
+
+        let mut n = NreplStream::persistent(&addr.into()).unwrap();
+        n.set_timeouts(Timeouts {
+            idle: Some(Duration::from_millis(50)),
+            ..Timeouts::default()
+        });
+
+        match n.op(Op::new("eval")) {
+            Err(Error::IdleTimeout { .. }) => (),
+            res => panic!("expected idle timeout, got: {:?}", res),
+        }
+
+        let timeouts = Timeouts {
+            read: Some(Duration::from_millis(50)),
+            idle: Some(Duration::from_secs(5)),
+            ..Timeouts::default()
+        };
+        let pending = n.send_with_timeouts(Op::new("eval"), timeouts).unwrap();
+
+        match pending.wait() {
+            Err(Error::ReadTimeout { .. }) => (),
+            res => panic!("expected read timeout, got: {:?}", res),
+        }
+
+        let pending = n.send(Op::new("eval")).unwrap();
+        close_tx.send(()).unwrap();
+
+        match pending.wait() {
+            Err(Error::ServerClosed { .. }) => (),
+            res => panic!("expected closed connection, got: {:?}", res),
+        }
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn addr_string_roundtrip_test() {
+        for s in [
+            "127.0.0.1:7888",
+            "[::1]:7888",
+            "/run/nrepl.sock",
+            "tls://127.0.0.1:7888",
+            "tls://nrepl.local@127.0.0.1:7888",
+        ] {
+            let addr: Addr = s.parse().unwrap();
+            assert_eq!(addr.to_string(), s);
+        }
+
+        assert_eq!(
+            "/run/nrepl.sock".parse::<Addr>().unwrap(),
+            Addr::Unix(PathBuf::from("/run/nrepl.sock"))
+        );
+        assert!("nrepl.sock".parse::<Addr>().is_err());
+    }
+
+    #[test]
+    fn resolve_addr_test() {
+        let local: SocketAddr = "127.0.0.1:7888".parse().unwrap();
+
+        for s in ["7888", "127.0.0.1:7888", "nrepl://127.0.0.1:7888/"] {
+            assert_eq!(Addr::resolve(s, "127.0.0.1").unwrap(), Addr::Tcp(local));
+        }
+
+        assert_eq!(
+            Addr::resolve("[::1]:7888", "127.0.0.1").unwrap(),
+            Addr::Tcp("[::1]:7888".parse().unwrap())
+        );
+        assert_eq!(
+            Addr::resolve("7888", "::1").unwrap(),
+            Addr::Tcp("[::1]:7888".parse().unwrap())
+        );
+        assert_eq!(
+            Addr::resolve("tls://127.0.0.1:7888", "127.0.0.1").unwrap(),
+            Addr::Tls {
+                addr: local,
+                server_name: None
+            }
+        );
+
+        match Addr::resolve("localhost:7888", "127.0.0.1").unwrap() {
+            Addr::Tcp(addr) => assert!(addr.ip().is_loopback()),
+            addr => panic!("expected tcp address, got: {}", addr),
+        }
+
+        assert!(Addr::resolve("localhost", "127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn detects_edn_transport_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Answers to the probe and then to the op, both over the same connection
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(tcp.try_clone().unwrap());
+            let mut w = tcp;
+
+            for _ in 0..2 {
+                let mut req = Codec::Edn.decode(&mut r).unwrap();
+                let id = req.remove("id").unwrap();
+                let op = req.remove("op").unwrap();
+                let resp = format!(
+                    "{{:id {}, :value {}, :status #{{:done}}}}",
+                    edn::to_string(&id),
+                    edn::to_string(&op)
+                );
+                w.write_all(resp.as_bytes()).unwrap();
+            }
+        });
+
+        let mut n = NreplStream::persistent(&addr.into()).unwrap();
+        n.set_codec(None);
+
+        let res = n.op(Op::new("eval")).unwrap();
+        assert_eq!(n.codec(), Some(Codec::Edn));
+        assert!(res.status().is_done());
+
+        let value = res.into_resps().pop().unwrap().remove("value").unwrap();
+        assert_eq!(bencode::try_into_string(value).unwrap(), "eval");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn need_input_is_answered_with_stdin_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Asks for input, then echoes it back as the value
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(tcp.try_clone().unwrap());
+            let mut w = tcp;
+            let id = read_resp(&mut r).unwrap().id().unwrap();
+
+            let mut need_input: HashMap<&str, BencodeValue> = HashMap::new();
+            need_input.insert("id", BencodeValue::Bytes(id.clone().into_bytes()));
+            need_input.insert("session", BencodeValue::Bytes(b"sess".to_vec()));
+            need_input.insert(
+                "status",
+                BencodeValue::List(vec![BencodeValue::Bytes(b"need-input".to_vec())]),
+            );
+            w.write_all(&serde_bencode::to_bytes(&need_input).unwrap())
+                .unwrap();
+
+            let mut stdin = read_resp(&mut r).unwrap();
+            assert_eq!(
+                bencode::try_into_string(stdin.remove("op").unwrap()).unwrap(),
+                "stdin"
+            );
+            assert_eq!(
+                bencode::try_into_string(stdin.remove("session").unwrap()).unwrap(),
+                "sess"
+            );
+
+            let mut done: HashMap<&str, BencodeValue> = HashMap::new();
+            done.insert("id", BencodeValue::Bytes(id.into_bytes()));
+            done.insert("value", stdin.remove("stdin").unwrap());
+            done.insert(
+                "status",
+                BencodeValue::List(vec![BencodeValue::Bytes(b"done".to_vec())]),
+            );
+            w.write_all(&serde_bencode::to_bytes(&done).unwrap())
+                .unwrap();
+        });
+
+        let mut n = NreplStream::persistent(&addr.into()).unwrap();
+        n.set_input(Box::new(std::io::Cursor::new(b"hello\n".to_vec())));
+
+        let res = n.op(Op::new("eval")).unwrap();
+        let value = res.into_resps().pop().unwrap().remove("value").unwrap();
+        assert_eq!(bencode::try_into_string(value).unwrap(), "hello\n");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn interrupter_refers_to_pending_op_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Evaluation ends only when it's interrupted
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(tcp.try_clone().unwrap());
+            let mut w = tcp;
+            let id = read_resp(&mut r).unwrap().id().unwrap();
+
+            let mut interrupt = read_resp(&mut r).unwrap();
+            assert_eq!(
+                bencode::try_into_string(interrupt.remove("op").unwrap()).unwrap(),
+                "interrupt"
+            );
+            assert_eq!(
+                bencode::try_into_string(interrupt.remove("interrupt-id").unwrap()).unwrap(),
+                id
+            );
+            assert_eq!(
+                bencode::try_into_string(interrupt.remove("session").unwrap()).unwrap(),
+                "sess"
+            );
+
+            let mut done: HashMap<&str, BencodeValue> = HashMap::new();
+            done.insert("id", BencodeValue::Bytes(id.into_bytes()));
+            done.insert(
+                "status",
+                BencodeValue::List(vec![
+                    BencodeValue::Bytes(b"done".to_vec()),
+                    BencodeValue::Bytes(b"interrupted".to_vec()),
+                ]),
+            );
+            w.write_all(&serde_bencode::to_bytes(&done).unwrap())
+                .unwrap();
+        });
+
+        let n = NreplStream::persistent(&addr.into()).unwrap();
+        let pending = n.send(Op::new("eval").arg("session", "sess")).unwrap();
+
+        pending.interrupter().unwrap().interrupt().unwrap();
+        assert!(pending.wait().unwrap().status().is_interrupted());
+
+        server.join().unwrap();
     }
 }
@@ -0,0 +1,402 @@
+//! Scriptable fake nrepl for tests, no JVM required.
+//!
+//! `clone`, `describe`, `ls-sessions` and `close` work like in real nrepl. `eval` and `info`
+//! answer with replies scripted for their `code` and `symbol`, any op can be scripted with
+//! `MockNrepl::on`. Replies can be slow, split in pieces or cut off, to see how clients cope.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use unrepl::nrepl::mock::{MockNrepl, Reply};
+//!
+//! let slow = Reply::new()
+//!     .out("adding\n")
+//!     .delay(Duration::from_secs(1))
+//!     .value("3")
+//!     .done();
+//! let server = MockNrepl::new().eval("(+ 1 2)", slow).start().unwrap();
+//!
+//! let nrepl = unrepl::nrepl::NreplStream::new(server.addr()).unwrap();
+//! ```
+
+use crate::bencode;
+use crate::nrepl::{Addr, Error, Resp, Socket};
+use serde_bencode::value::Value as BencodeValue;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::io::{BufReader, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type Fields = Vec<(Vec<u8>, BencodeValue)>;
+
+#[derive(Clone)]
+enum Step {
+    /// `id` and `session` of the request are added
+    Msg(Fields),
+    /// Message written in two halves with a pause between them
+    Split(Fields, Duration),
+    Delay(Duration),
+    /// Drops connection, in the middle of the next message if there's one
+    Close,
+}
+
+/// What nrepl does in response to a request, step by step
+#[derive(Clone, Default)]
+pub struct Reply {
+    steps: Vec<Step>,
+    split_next: Option<Duration>,
+}
+
+fn str_val(s: &str) -> BencodeValue {
+    BencodeValue::Bytes(s.as_bytes().to_vec())
+}
+
+impl Reply {
+    pub fn new() -> Reply {
+        Reply::default()
+    }
+
+    /// Response with arbitrary fields
+    pub fn msg(self, fields: Vec<(&str, BencodeValue)>) -> Reply {
+        self.raw_msg(fields.into_iter().map(|(k, v)| (k.as_bytes(), v)).collect())
+    }
+
+    /// Response with keys which don't have to be UTF-8, like broken middleware could send
+    pub fn raw_msg(mut self, fields: Vec<(&[u8], BencodeValue)>) -> Reply {
+        let fields = fields.into_iter().map(|(k, v)| (k.to_vec(), v)).collect();
+
+        let step = match self.split_next.take() {
+            Some(pause) => Step::Split(fields, pause),
+            None => Step::Msg(fields),
+        };
+        self.steps.push(step);
+        self
+    }
+
+    pub fn value(self, value: &str) -> Reply {
+        self.msg(vec![("value", str_val(value)), ("ns", str_val("user"))])
+    }
+
+    pub fn out(self, out: &str) -> Reply {
+        self.msg(vec![("out", str_val(out))])
+    }
+
+    pub fn err(self, err: &str) -> Reply {
+        self.msg(vec![("err", str_val(err))])
+    }
+
+    /// Evaluation has thrown `ex`
+    pub fn ex(self, ex: &str) -> Reply {
+        self.msg(vec![
+            ("ex", str_val(ex)),
+            ("root-ex", str_val(ex)),
+            ("status", BencodeValue::List(vec![str_val("eval-error")])),
+        ])
+    }
+
+    pub fn status(self, flags: &[&str]) -> Reply {
+        self.msg(vec![(
+            "status",
+            BencodeValue::List(flags.iter().map(|f| str_val(f)).collect()),
+        )])
+    }
+
+    pub fn done(self) -> Reply {
+        self.status(&["done"])
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Reply {
+        self.steps.push(Step::Delay(delay));
+        self
+    }
+
+    /// Next message is written in two halves, with `pause` between them
+    pub fn split(mut self, pause: Duration) -> Reply {
+        self.split_next = Some(pause);
+        self
+    }
+
+    /// Connection is dropped, with half of the next message written if there's one
+    pub fn close(mut self) -> Reply {
+        self.steps.push(Step::Close);
+        self
+    }
+}
+
+/// Scripted nrepl which is yet to be started
+#[derive(Default)]
+pub struct MockNrepl {
+    evals: HashMap<String, Reply>,
+    infos: HashMap<String, Reply>,
+    ops: HashMap<String, Reply>,
+}
+
+impl MockNrepl {
+    pub fn new() -> MockNrepl {
+        MockNrepl::default()
+    }
+
+    /// `eval` of exactly this `code`, others get `nil`
+    pub fn eval(mut self, code: &str, reply: Reply) -> MockNrepl {
+        self.evals.insert(code.to_string(), reply);
+        self
+    }
+
+    /// `info` of `symbol`, others get `no-info`
+    pub fn info(mut self, symbol: &str, reply: Reply) -> MockNrepl {
+        self.infos.insert(symbol.to_string(), reply);
+        self
+    }
+
+    /// Any request of `op`, takes precedence over built-in ops
+    pub fn on(mut self, op: &str, reply: Reply) -> MockNrepl {
+        self.ops.insert(op.to_string(), reply);
+        self
+    }
+
+    /// Listens on a free port of localhost
+    pub fn start(self) -> Result<MockServer, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = Addr::Tcp(listener.local_addr()?);
+        let state = Arc::new(State::new(self));
+
+        {
+            let state = state.clone();
+            thread::spawn(move || {
+                for client in listener.incoming().flatten() {
+                    spawn_client(Box::new(client), &state);
+                }
+            });
+        }
+
+        Ok(MockServer { addr, state })
+    }
+
+    /// Listens on unix socket at `path`
+    pub fn start_unix(self, path: &Path) -> Result<MockServer, Error> {
+        let listener = UnixListener::bind(path)?;
+        let addr = Addr::Unix(path.to_path_buf());
+        let state = Arc::new(State::new(self));
+
+        {
+            let state = state.clone();
+            thread::spawn(move || {
+                for client in listener.incoming().flatten() {
+                    spawn_client(Box::new(client), &state);
+                }
+            });
+        }
+
+        Ok(MockServer { addr, state })
+    }
+}
+
+struct State {
+    script: MockNrepl,
+    sessions: Mutex<BTreeSet<String>>,
+    next_session: AtomicUsize,
+    requests: Mutex<Vec<Resp>>,
+}
+
+impl State {
+    fn new(script: MockNrepl) -> State {
+        State {
+            script,
+            sessions: Mutex::new(BTreeSet::new()),
+            next_session: AtomicUsize::new(1),
+            requests: Mutex::new(vec![]),
+        }
+    }
+
+    fn describe(&self) -> Reply {
+        let ops = ["clone", "describe", "ls-sessions", "close", "eval", "info"]
+            .iter()
+            .map(|op| op.to_string())
+            .chain(self.script.ops.keys().cloned())
+            .map(|op| (op.into_bytes(), BencodeValue::Dict(HashMap::new())))
+            .collect();
+
+        Reply::new()
+            .msg(vec![("ops", BencodeValue::Dict(ops))])
+            .done()
+    }
+
+    /// `session` is known one, requests with unknown sessions don't get here
+    fn reply(&self, op: &str, req: &Resp, session: Option<&str>) -> Reply {
+        let arg = |name: &str| {
+            req.get(name)
+                .and_then(|v| bencode::try_into_string(v.clone()).ok())
+                .unwrap_or_default()
+        };
+
+        if let Some(reply) = self.script.ops.get(op) {
+            return reply.clone();
+        }
+
+        match op {
+            "clone" => {
+                let id = format!(
+                    "mock-session-{}",
+                    self.next_session.fetch_add(1, Ordering::SeqCst)
+                );
+                self.sessions.lock().unwrap().insert(id.clone());
+
+                Reply::new().msg(vec![("new-session", str_val(&id))]).done()
+            }
+            "describe" => self.describe(),
+            "ls-sessions" => {
+                let sessions = self.sessions.lock().unwrap();
+
+                Reply::new()
+                    .msg(vec![(
+                        "sessions",
+                        BencodeValue::List(sessions.iter().map(|s| str_val(s)).collect()),
+                    )])
+                    .done()
+            }
+            "close" => {
+                if let Some(session) = session {
+                    self.sessions.lock().unwrap().remove(session);
+                }
+                Reply::new().status(&["done", "session-closed"])
+            }
+            "eval" => match self.script.evals.get(&arg("code")) {
+                Some(reply) => reply.clone(),
+                None => Reply::new().value("nil").done(),
+            },
+            "info" => match self.script.infos.get(&arg("symbol")) {
+                Some(reply) => reply.clone(),
+                None => Reply::new().status(&["done", "no-info"]),
+            },
+            _ => Reply::new().status(&["done", "error", "unknown-op"]),
+        }
+    }
+}
+
+type Writer = Arc<Mutex<Box<dyn Socket>>>;
+
+fn encode(fields: &Fields, req: &Resp) -> Vec<u8> {
+    let mut msg: HashMap<Vec<u8>, BencodeValue> = fields.iter().cloned().collect();
+
+    for key in ["id", "session"].iter() {
+        if let (Some(val), false) = (req.get(*key), msg.contains_key(key.as_bytes())) {
+            msg.insert(key.as_bytes().to_vec(), val.clone());
+        }
+    }
+
+    serde_bencode::to_bytes(&BencodeValue::Dict(msg)).unwrap()
+}
+
+/// Returns `false` once connection is closed
+fn play(reply: &Reply, req: &Resp, w: &Writer) -> bool {
+    let mut steps = reply.steps.iter().peekable();
+
+    while let Some(step) = steps.next() {
+        let res = match step {
+            Step::Msg(fields) => w.lock().unwrap().write_all(&encode(fields, req)),
+            Step::Split(fields, pause) => {
+                let bs = encode(fields, req);
+                let (head, tail) = bs.split_at(bs.len() / 2);
+                let mut w = w.lock().unwrap();
+
+                w.write_all(head)
+                    .and_then(|_| w.flush())
+                    .map(|_| thread::sleep(*pause))
+                    .and_then(|_| w.write_all(tail))
+            }
+            Step::Delay(delay) => {
+                thread::sleep(*delay);
+                Ok(())
+            }
+            Step::Close => {
+                let mut w = w.lock().unwrap();
+
+                if let Some(Step::Msg(fields)) | Some(Step::Split(fields, _)) = steps.peek() {
+                    let bs = encode(fields, req);
+                    let _ = w.write_all(&bs[..bs.len() / 2]);
+                }
+                let _ = w.shutdown_socket();
+                return false;
+            }
+        };
+
+        if res.is_err() {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn serve_client(client: Box<dyn Socket>, state: Arc<State>) {
+    let reader = match client.try_clone_socket() {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    let w: Writer = Arc::new(Mutex::new(client));
+    let mut r = BufReader::new(reader);
+
+    while let Ok(Some(val)) = bencode::read_value(&mut r) {
+        let req = match Resp::try_from(val) {
+            Ok(req) => req,
+            Err(_) => continue,
+        };
+        state.requests.lock().unwrap().push(req.clone());
+
+        let state = state.clone();
+        let w = w.clone();
+
+        // Slow replies don't hold back others, like in real nrepl
+        thread::spawn(move || {
+            let op = req
+                .get("op")
+                .and_then(|v| bencode::try_into_string(v.clone()).ok())
+                .unwrap_or_default();
+            let session = req
+                .get("session")
+                .and_then(|v| bencode::try_into_string(v.clone()).ok());
+            let known = session
+                .as_ref()
+                .map(|s| state.sessions.lock().unwrap().contains(s));
+
+            let reply = match known {
+                Some(false) => Reply::new().status(&["done", "error", "unknown-session"]),
+                _ => state.reply(&op, &req, session.as_deref()),
+            };
+
+            play(&reply, &req, &w);
+        });
+    }
+}
+
+fn spawn_client(client: Box<dyn Socket>, state: &Arc<State>) {
+    let state = state.clone();
+    thread::spawn(move || serve_client(client, state));
+}
+
+/// Running mock, it stops together with the process
+pub struct MockServer {
+    addr: Addr,
+    state: Arc<State>,
+}
+
+impl MockServer {
+    pub fn addr(&self) -> &Addr {
+        &self.addr
+    }
+
+    /// Every request received so far
+    pub fn requests(&self) -> Vec<Resp> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Like nrepl was restarted, known sessions are answered with `unknown-session`
+    pub fn forget_sessions(&self) {
+        self.state.sessions.lock().unwrap().clear();
+    }
+}
@@ -1,29 +1,61 @@
 use crate::bencode as bc;
 use crate::config::Session;
+use crate::edn;
 use crate::nrepl;
 use failure::{Error as StdError, Fail};
-use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_bencode::value::Value as BencodeValue;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::convert::From;
+use std::time::Duration;
 
 #[derive(Debug, Fail)]
 pub enum Error {
-    #[fail(display = "sent `{}`, but no session id in response", op)]
-    NoSessionIdInResponse { op: String },
-    #[fail(display = "Sent `{}`, but no sessions list in response", op)]
-    NoSessionsInResponse { op: String },
-    #[fail(
-        display = "Sent `{}`, expected to find field `{}`, but it wasn't in nrepl response",
-        op, field
-    )]
-    FieldNotFound { op: String, field: String },
+    #[fail(display = "Sent `{}`, but couldn't read the response: {}", op, err)]
+    BadResponse { op: String, err: bc::de::Error },
     #[fail(display = "Unexpected nrepl status: {}", status)]
     BadStatus { status: String },
-    #[fail(display = "Having two 'ops' dicts in response to 'describe' op")]
-    DuplicatedOpsInResponse,
     #[fail(display = "'info' op is not available")]
     InfoOpUnavailable,
+    #[fail(display = "Neither of {:?} ops is available", ops)]
+    OpsUnavailable { ops: Vec<&'static str> },
+}
+
+/// Fails when nrepl reports that the op or the evaluation has failed
+pub fn check_status(res: nrepl::Responses) -> Result<Vec<nrepl::Resp>, StdError> {
+    let status = res.status();
+
+    if status.is_error() || status.is_eval_error() || status.is_interrupted() {
+        return Err(Error::BadStatus {
+            status: status.name(),
+        }
+        .into());
+    }
+
+    Ok(res.into_resps())
+}
+
+/// Clojure string literal, values go into evaluated code only as strings
+pub(crate) fn clj_str(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Reads fields of all responses to `op` into `T`, fields of later responses win
+pub fn decode<T: DeserializeOwned>(op: &str, resps: Vec<nrepl::Resp>) -> Result<T, StdError> {
+    let mut fields = HashMap::new();
+
+    for mut resp in resps {
+        fields.extend(resp.drain());
+    }
+
+    nrepl::Resp::from(fields)
+        .decode()
+        .map_err(|err| Error::BadResponse {
+            op: op.to_string(),
+            err,
+        })
+        .map_err(StdError::from)
 }
 
 pub struct CloneSession {
@@ -38,40 +70,28 @@ impl CloneSession {
 
 impl From<&CloneSession> for nrepl::Op {
     fn from(CloneSession { session }: &CloneSession) -> nrepl::Op {
-        let mut args: Vec<(String, String)> = vec![];
-
-        if let Some(s) = session {
-            args.push(("session".to_string(), s.to_string()))
-        }
-
-        nrepl::Op::new("clone".to_string(), args)
+        nrepl::Op::new("clone").opt_arg("session", session.as_deref())
     }
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct CloneResp {
+    new_session: String,
+}
+
 impl nrepl::NreplOp<String> for CloneSession {
     type Error = StdError;
 
     fn send(&self, n: &nrepl::NreplStream) -> Result<String, StdError> {
-        match n.op(self)? {
-            nrepl::Status::Done(resps) => {
-                for mut resp in resps {
-                    if let Some(session_id) = resp.remove("new-session") {
-                        return Ok(bc::try_into_string(session_id)?);
-                    }
-                }
-                return Err(Error::NoSessionIdInResponse {
-                    op: "clone".to_string(),
-                }
-                .into());
-            }
-            status => Err(Error::BadStatus {
-                status: status.name(),
-            }
-            .into()),
-        }
+        let resps = check_status(n.typed_op(self)?)?;
+        let resp: CloneResp = decode("clone", resps)?;
+
+        Ok(resp.new_session)
     }
 }
 
+#[derive(Default)]
 pub struct LsSessions {}
 
 impl LsSessions {
@@ -82,32 +102,23 @@ impl LsSessions {
 
 impl From<&LsSessions> for nrepl::Op {
     fn from(_op: &LsSessions) -> nrepl::Op {
-        nrepl::Op::new("ls-sessions".to_string(), vec![])
+        nrepl::Op::new("ls-sessions")
     }
 }
 
+#[derive(Deserialize)]
+struct LsSessionsResp {
+    sessions: Vec<String>,
+}
+
 impl nrepl::NreplOp<Vec<String>> for LsSessions {
     type Error = StdError;
 
     fn send(self: &LsSessions, n: &nrepl::NreplStream) -> Result<Vec<String>, Self::Error> {
-        match n.op(self)? {
-            nrepl::Status::Done(resps) => {
-                for mut resp in resps {
-                    if let Some(sessions) = resp.remove("sessions") {
-                        return Ok(bc::try_into_str_vec(sessions)?);
-                    }
-                }
-                return Err(Error::NoSessionsInResponse {
-                    op: "ls-sessions".to_string(),
-                }
-                .into());
-            }
+        let resps = check_status(n.typed_op(self)?)?;
+        let resp: LsSessionsResp = decode("ls-sessions", resps)?;
 
-            status => Err(Error::BadStatus {
-                status: status.name(),
-            }
-            .into()),
-        }
+        Ok(resp.sessions)
     }
 }
 
@@ -170,148 +181,119 @@ impl From<&Info> for nrepl::Op {
             symbol,
         }: &Info,
     ) -> nrepl::Op {
-        nrepl::Op::new(
-            "info".to_string(),
-            vec![
-                ("symbol".to_string(), symbol.to_string()),
-                ("ns".to_string(), ns.to_string()),
-                ("session".to_string(), session.id()),
-            ],
-        )
+        nrepl::Op::new("info")
+            .arg("symbol", symbol)
+            .arg("ns", ns)
+            .arg("session", &session.id())
     }
 }
 
-fn get_int_bencode(resp: &mut nrepl::Resp, k: &str) -> Result<Option<i64>, StdError> {
-    if let Some(n) = resp.remove(k) {
-        Ok(Some(bc::try_into_int(n)?))
-    } else {
-        Ok(None)
-    }
-}
-
-fn get_str_bencode(resp: &mut nrepl::Resp, k: &str) -> Result<Option<String>, StdError> {
-    if let Some(s) = resp.remove(k) {
-        Ok(Some(bc::try_into_string(s)?))
-    } else {
-        Ok(None)
-    }
-}
-
-fn get_str_list_bencode(resp: &mut nrepl::Resp, k: &str) -> Result<Option<Vec<String>>, StdError> {
-    if let Some(sl) = resp.remove(k) {
-        Ok(Some(bc::try_into_str_vec(sl)?))
-    } else {
-        Ok(None)
-    }
-}
 impl nrepl::NreplOp<Option<InfoResponseType>> for Info {
     type Error = StdError;
 
-    // This function is quite bulky, but currently i don't see (or i am not interested in) how to
-    // organize it better.
-    // I wanted to have a greater control under parsing SYMBOL/NS/JavaClass
     fn send(self: &Info, n: &nrepl::NreplStream) -> Result<Option<InfoResponseType>, Self::Error> {
         if !self.session.is_op_available("info") {
             return Err(Error::InfoOpUnavailable.into());
         }
 
-        match n.op(self)? {
-            nrepl::Status::Done(mut resps) | nrepl::Status::State(mut resps) => {
-                let mut resp = resps.pop().unwrap();
-                // "line" is required for symbols, but not namespace, TODO: Improve this
-                let line: Option<i64> = get_int_bencode(&mut resp, "line")?;
-                let column: Option<i64> = get_int_bencode(&mut resp, "column")?;
-
-                // It's weird, but valid:
-                // When we received {file: [...]} it means that given given symbol was a java class,
-                // and we have nothing to do with Java Class here.
-                if let Some(v) = resp.get("file") {
-                    if let serde_bencode::value::Value::List(_) = v {
-                        return Ok(None);
-                    }
-                }
+        let res = n.typed_op(self)?;
 
-                // This is required field, we can't skip it
-                let file: String =
-                    get_str_bencode(&mut resp, "file")?.ok_or(Error::FieldNotFound {
-                        op: "info".to_string(),
-                        field: "file".to_string(),
-                    })?;
-
-                // Actually, resource is not mandatory, TODO: Improve this
-                let resource: String =
-                    get_str_bencode(&mut resp, "resource")?.ok_or(Error::FieldNotFound {
-                        op: "info".to_string(),
-                        field: "resource".to_string(),
-                    })?;
-
-                let doc: Option<String> = get_str_bencode(&mut resp, "doc")?;
-                let name: Option<String> = get_str_bencode(&mut resp, "name")?;
-                let arglist: Option<String> = get_str_bencode(&mut resp, "arglists-str")?;
-                let ns: Option<String> = get_str_bencode(&mut resp, "ns")?;
-                // We are only interested in presence of this field, not it's content
-                // TODO: Check if "macro" could be other than "true"
-                let is_macro: Option<String> = get_str_bencode(&mut resp, "macro")?;
-                let spec: Option<String> =
-                    get_str_list_bencode(&mut resp, "spec")?.map(|spec_list| spec_list.join(" "));
-                let docstr: String;
-
-                // There's only single way to distinguish NS from SYMBOL is by absence of
-                // column/name/arglist
-                if line.is_some() && column.is_none() && name.is_none() && arglist.is_none() {
-                    docstr = vec![ns, doc]
-                        .into_iter()
-                        .flat_map(|v| v)
-                        .collect::<Vec<String>>()
-                        .join("\n");
-
-                    Ok(Some(InfoResponseType::Ns(InfoResponse::new(
-                        line.unwrap(),
-                        column,
-                        file,
-                        resource,
-                        docstr,
-                    ))))
-                // Otherwise it's SYMBOL
-                } else {
-                    docstr = vec![
-                        String::from(if is_macro.is_some() { "macro" } else { "" }),
-                        vec![ns, name]
-                            .into_iter()
-                            .flat_map(|v| v)
-                            .collect::<Vec<String>>()
-                            .join("/"),
-                        arglist
-                            .unwrap_or("".to_string())
-                            .split("\n")
-                            .map(|s| format!("({})", s))
-                            .collect::<Vec<String>>()
-                            .join("\n"),
-                        doc.unwrap_or(String::new()),
-                        spec.unwrap_or(String::new()),
-                    ]
-                    .into_iter()
-                    .filter(|s| !s.is_empty())
-                    .collect::<Vec<String>>()
-                    .join("\n");
-
-                    Ok(Some(InfoResponseType::Symbol(InfoResponse::new(
-                        line.unwrap(),
-                        column,
-                        file,
-                        resource,
-                        docstr,
-                    ))))
-                }
-            }
+        if res.status().is_no_info() {
+            return Ok(None);
+        }
 
-            nrepl::Status::NoInfo(_) => Ok(None),
+        let mut resps = check_status(res)?;
 
-            status => Err(Error::BadStatus {
-                status: status.name(),
-            }
-            .into()),
-        }
+        parse_info(resps.pop().unwrap())
+    }
+}
+
+/// Fields of `info` response, of a symbol or a namespace
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct InfoFields {
+    /// Required for symbols, but not namespaces
+    line: Option<i64>,
+    column: Option<i64>,
+    file: String,
+    resource: String,
+    doc: Option<String>,
+    name: Option<String>,
+    #[serde(rename = "arglists-str")]
+    arglist: Option<String>,
+    ns: Option<String>,
+    #[serde(rename = "macro", default)]
+    is_macro: bool,
+    spec: Option<Vec<String>>,
+}
+
+/// Reads `info` response of cider-nrepl, prepl backend produces the same fields
+pub(crate) fn parse_info(resp: nrepl::Resp) -> Result<Option<InfoResponseType>, StdError> {
+    // It's weird, but valid:
+    // When we received {file: [...]} it means that given given symbol was a java class,
+    // and we have nothing to do with Java Class here.
+    if let Some(BencodeValue::List(_)) = resp.get("file") {
+        return Ok(None);
+    }
+
+    let InfoFields {
+        line,
+        column,
+        file,
+        resource,
+        doc,
+        name,
+        arglist,
+        ns,
+        is_macro,
+        spec,
+    } = decode("info", vec![resp])?;
+    let spec = spec.map(|spec_list| spec_list.join(" "));
+    let docstr: String;
+
+    // There's only single way to distinguish NS from SYMBOL is by absence of
+    // column/name/arglist
+    if let (Some(line), None, None, None) = (line, column, &name, &arglist) {
+        docstr = vec![ns, doc]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        Ok(Some(InfoResponseType::Ns(InfoResponse::new(
+            line, column, file, resource, docstr,
+        ))))
+    // Otherwise it's SYMBOL
+    } else {
+        let line = line.ok_or_else(|| Error::BadResponse {
+            op: "info".to_string(),
+            err: serde::de::Error::missing_field("line"),
+        })?;
+
+        docstr = vec![
+            String::from(if is_macro { "macro" } else { "" }),
+            vec![ns, name]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<String>>()
+                .join("/"),
+            arglist
+                .unwrap_or("".to_string())
+                .split("\n")
+                .map(|s| format!("({})", s))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            doc.unwrap_or_default(),
+            spec.unwrap_or_default(),
+        ]
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n");
+
+        Ok(Some(InfoResponseType::Symbol(InfoResponse::new(
+            line, column, file, resource, docstr,
+        ))))
     }
 }
 
@@ -338,50 +320,653 @@ impl From<&GetNsName> for nrepl::Op {
             session,
         }: &GetNsName,
     ) -> nrepl::Op {
-        nrepl::Op::new(
-            "eval".to_string(),
-            vec![
-                (
-                    "code".to_string(),
-                    format!(
-                        "
+        let code = format!(
+            "
              (do
                 (require 'clojure.tools.namespace.file)
                 (nth (clojure.tools.namespace.file/read-file-ns-decl \"{}\") 1)
              )",
-                        source_path
-                    ),
-                ),
-                ("session".to_string(), session.id()),
-            ],
-        )
+            source_path
+        );
+
+        nrepl::Op::new("eval")
+            .arg("code", &code)
+            .arg("session", &session.id())
     }
 }
 
+#[derive(Deserialize)]
+struct EvalValue {
+    value: Option<String>,
+}
+
 impl nrepl::NreplOp<Option<String>> for GetNsName {
     type Error = StdError;
 
+    // The first `require` of clojure.tools.namespace could take a while
+    fn timeouts(&self, stream_timeouts: nrepl::Timeouts) -> nrepl::Timeouts {
+        nrepl::Timeouts {
+            idle: stream_timeouts
+                .idle
+                .map(|idle| idle.max(Duration::from_secs(30))),
+            ..stream_timeouts
+        }
+    }
+
     fn send(&self, n: &nrepl::NreplStream) -> Result<Option<String>, Self::Error> {
-        match n.op(self)? {
-            nrepl::Status::Done(resps) => {
-                let mut value: Option<String> = None;
-
-                for mut resp in resps {
-                    if let Some(val) = resp.remove("value") {
-                        value = Some(bc::try_into_string(val)?)
-                    }
+        let resps = check_status(n.typed_op(self)?)?;
+        let resp: EvalValue = decode("eval", resps)?;
+
+        Ok(resp.value)
+    }
+}
+
+/// Evaluates `code`, positions in errors and metadata of defined vars are taken from
+/// `file`, `line` and `column` when they're set
+pub struct Eval {
+    session: Session,
+    code: String,
+    ns: Option<String>,
+    file: Option<String>,
+    line: Option<i64>,
+    column: Option<i64>,
+}
+
+impl Eval {
+    pub fn new(session: Session, code: String) -> Self {
+        Self {
+            session,
+            code,
+            ns: None,
+            file: None,
+            line: None,
+            column: None,
+        }
+    }
+
+    pub fn set_ns(&mut self, ns: String) {
+        self.ns = Some(ns);
+    }
+
+    pub fn set_file(&mut self, file: String) {
+        self.file = Some(file);
+    }
+
+    pub fn set_line(&mut self, line: i64) {
+        self.line = Some(line);
+    }
+
+    pub fn set_column(&mut self, column: i64) {
+        self.column = Some(column);
+    }
+}
+
+impl From<&Eval> for nrepl::Op {
+    fn from(
+        Eval {
+            session,
+            code,
+            ns,
+            file,
+            line,
+            column,
+        }: &Eval,
+    ) -> nrepl::Op {
+        let mut op = nrepl::Op::new("eval")
+            .arg("code", code)
+            .arg("session", &session.id())
+            .opt_arg("ns", ns.as_deref())
+            .opt_arg("file", file.as_deref());
+
+        if let Some(line) = line {
+            op = op.int("line", *line);
+        }
+        if let Some(column) = column {
+            op = op.int("column", *column);
+        }
+        op
+    }
+}
+
+/// Single response to `eval`, it usually has one of the fields
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EvalMsg {
+    pub value: Option<String>,
+    pub out: Option<String>,
+    pub err: Option<String>,
+    pub ex: Option<String>,
+    pub root_ex: Option<String>,
+    /// Namespace the value was evaluated in
+    pub ns: Option<String>,
+}
+
+impl EvalMsg {
+    pub fn read(resp: nrepl::Resp) -> Result<EvalMsg, StdError> {
+        decode("eval", vec![resp])
+    }
+}
+
+/// Everything evaluation has printed, along with its result
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EvalResult {
+    /// Printed values, one for each evaluated form
+    pub values: Vec<String>,
+    pub out: String,
+    pub err: String,
+    /// Set when evaluation has thrown
+    pub ex: Option<String>,
+    /// Class of the original cause of `ex`
+    pub root_ex: Option<String>,
+    /// Namespace after evaluation, `in-ns` in the code changes it
+    pub ns: Option<String>,
+}
+
+impl EvalResult {
+    pub fn add(&mut self, msg: EvalMsg) {
+        self.values.extend(msg.value);
+        self.out.push_str(&msg.out.unwrap_or_default());
+        self.err.push_str(&msg.err.unwrap_or_default());
+        self.ex = msg.ex.or_else(|| self.ex.take());
+        self.root_ex = msg.root_ex.or_else(|| self.root_ex.take());
+        self.ns = msg.ns.or_else(|| self.ns.take());
+    }
+}
+
+/// Thrown exceptions are in the result, only failures of the op itself are errors
+fn read_eval(res: nrepl::Responses) -> Result<EvalResult, StdError> {
+    if res.status().is_error() || res.status().is_interrupted() {
+        return Err(Error::BadStatus {
+            status: res.status().name(),
+        }
+        .into());
+    }
+
+    let mut result = EvalResult::default();
+
+    for resp in res.into_resps() {
+        result.add(EvalMsg::read(resp)?);
+    }
+
+    Ok(result)
+}
+
+impl nrepl::NreplOp<EvalResult> for Eval {
+    type Error = StdError;
+
+    fn send(&self, n: &nrepl::NreplStream) -> Result<EvalResult, Self::Error> {
+        read_eval(n.typed_op(self)?)
+    }
+}
+
+/// Evaluates contents of a file, responses are the same as of `eval`
+pub struct LoadFile {
+    session: Session,
+    /// Contents
+    file: String,
+    /// Compiler puts it in errors and metadata of vars, e.g. `/home/dev/app/src/my/app.clj`
+    file_path: String,
+    /// E.g. `app.clj`
+    file_name: String,
+}
+
+impl LoadFile {
+    pub fn new(session: Session, file: String, file_path: String, file_name: String) -> Self {
+        Self {
+            session,
+            file,
+            file_path,
+            file_name,
+        }
+    }
+}
+
+impl From<&LoadFile> for nrepl::Op {
+    fn from(
+        LoadFile {
+            session,
+            file,
+            file_path,
+            file_name,
+        }: &LoadFile,
+    ) -> nrepl::Op {
+        nrepl::Op::new("load-file")
+            .arg("file", file)
+            .arg("file-path", file_path)
+            .arg("file-name", file_name)
+            .arg("session", &session.id())
+    }
+}
+
+impl nrepl::NreplOp<EvalResult> for LoadFile {
+    type Error = StdError;
+
+    fn send(&self, n: &nrepl::NreplStream) -> Result<EvalResult, Self::Error> {
+        read_eval(n.typed_op(self)?)
+    }
+}
+
+/// Completion candidates for `prefix`, from cider-nrepl `complete` or nREPL 0.8+
+/// `completions`, whichever is there
+pub struct Complete {
+    session: Session,
+    ns: Option<String>,
+    prefix: String,
+}
+
+impl Complete {
+    pub fn new(session: Session, ns: Option<String>, prefix: String) -> Self {
+        Self {
+            session,
+            ns,
+            prefix,
+        }
+    }
+
+    fn op_name(&self) -> Option<&'static str> {
+        ["complete", "completions"]
+            .iter()
+            .find(|op| self.session.is_op_available(op))
+            .copied()
+    }
+}
+
+impl From<&Complete> for nrepl::Op {
+    fn from(complete: &Complete) -> nrepl::Op {
+        let op = nrepl::Op::new(complete.op_name().unwrap_or("completions"))
+            .arg("prefix", &complete.prefix)
+            .opt_arg("ns", complete.ns.as_deref())
+            .arg("session", &complete.session.id());
+        let arglists = BencodeValue::List(vec![BencodeValue::Bytes(b"arglists".to_vec())]);
+
+        // nREPL takes options in a map, cider-nrepl takes them as they are
+        match complete.op_name() {
+            Some("complete") => op.value("extra-metadata", arglists),
+            _ => op.dict("options", vec![("extra-metadata", arglists)]),
+        }
+    }
+}
+
+/// `arglists` is a list of strings like `"[f coll]"`, some versions send them joined
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Arglists {
+    Joined(String),
+    List(Vec<String>),
+}
+
+fn arglists<'de, D>(d: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match Arglists::deserialize(d)? {
+        Arglists::Joined(s) => s.lines().map(|l| l.to_string()).collect(),
+        Arglists::List(l) => l,
+    })
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Candidate {
+    pub candidate: String,
+    /// `function`, `macro`, `var`, `keyword`, `class`, `namespace`, `special-form` and so on
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub ns: Option<String>,
+    #[serde(default, deserialize_with = "arglists")]
+    pub arglists: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CompleteResp {
+    completions: Vec<Candidate>,
+}
+
+impl nrepl::NreplOp<Vec<Candidate>> for Complete {
+    type Error = StdError;
+
+    fn send(&self, n: &nrepl::NreplStream) -> Result<Vec<Candidate>, Self::Error> {
+        let op = self.op_name().ok_or(Error::OpsUnavailable {
+            ops: vec!["complete", "completions"],
+        })?;
+        let resps = check_status(n.typed_op(self)?)?;
+        let resp: CompleteResp = decode(op, resps)?;
+
+        Ok(resp.completions)
+    }
+}
+
+/// Arglists, type and docstring of a symbol, for hints while typing. Takes cider-nrepl
+/// `eldoc` or nREPL 0.8+ `lookup`, whichever is there, or evaluates lookup of var's metadata.
+pub struct Eldoc {
+    session: Session,
+    ns: String,
+    symbol: String,
+}
+
+impl Eldoc {
+    pub fn new(session: Session, ns: String, symbol: String) -> Self {
+        Self {
+            session,
+            ns,
+            symbol,
+        }
+    }
+
+    /// `None` means evaluation
+    fn op_name(&self) -> Option<&'static str> {
+        ["eldoc", "lookup"]
+            .iter()
+            .find(|op| self.session.is_op_available(op))
+            .copied()
+    }
+}
+
+impl From<&Eldoc> for nrepl::Op {
+    fn from(eldoc: &Eldoc) -> nrepl::Op {
+        let Eldoc {
+            session,
+            ns,
+            symbol,
+        } = eldoc;
+
+        match eldoc.op_name() {
+            Some(op) => nrepl::Op::new(op)
+                .arg("ns", ns)
+                .arg("sym", symbol)
+                // cider-nrepl before 0.22 reads `symbol`
+                .arg("symbol", symbol)
+                .arg("session", &session.id()),
+            // Same fields as in `lookup` response
+            None => {
+                let code = format!(
+                    "(clojure.core/let [ns (clojure.core/find-ns (clojure.core/symbol {ns}))
+                           v (clojure.core/when ns
+                               (clojure.core/ns-resolve ns (clojure.core/symbol {symbol})))
+                           m (clojure.core/meta v)]
+                       (clojure.core/when (clojure.core/var? v)
+                         {{:name (clojure.core/str (:name m))
+                          :ns (clojure.core/str (clojure.core/ns-name (:ns m)))
+                          :arglists (clojure.core/some-> (:arglists m) clojure.core/pr-str)
+                          :doc (:doc m)
+                          :macro (clojure.core/when (:macro m) \"true\")}}))",
+                    ns = clj_str(ns),
+                    symbol = clj_str(symbol)
+                );
+
+                nrepl::Op::new("eval")
+                    .arg("code", &code)
+                    .arg("session", &session.id())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EldocInfo {
+    pub name: Option<String>,
+    pub ns: Option<String>,
+    /// `function`, `macro`, `special-form`, `variable`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Like `[f coll]`
+    pub arglists: Vec<String>,
+    pub docstring: Option<String>,
+}
+
+/// Response of cider-nrepl `eldoc`
+#[derive(Deserialize)]
+struct CiderEldoc {
+    /// Parameters of each arity
+    eldoc: Option<Vec<Vec<String>>>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    ns: Option<String>,
+    name: Option<String>,
+    docstring: Option<String>,
+}
+
+/// Var's metadata, as `lookup` sends it
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct VarMeta {
+    name: Option<String>,
+    ns: Option<String>,
+    /// Printed, like `([f] [f coll])`
+    arglists: Option<String>,
+    doc: Option<String>,
+    #[serde(rename = "macro", default)]
+    is_macro: bool,
+    #[serde(default)]
+    special_form: bool,
+}
+
+#[derive(Deserialize)]
+struct LookupResp {
+    #[serde(default)]
+    info: VarMeta,
+}
+
+/// `([f] [f coll])` into `[f]` and `[f coll]`
+fn split_arglists(arglists: &str) -> Vec<String> {
+    let mut res = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in arglists.char_indices() {
+        match c {
+            '[' => {
+                if depth == 0 {
+                    start = i;
+                }
+                depth += 1;
+            }
+            ']' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    res.push(arglists[start..=i].to_string());
                 }
-                Ok(value)
             }
+            _ => (),
+        }
+    }
 
-            status => Err(Error::BadStatus {
-                status: status.name(),
+    res
+}
+
+impl From<VarMeta> for EldocInfo {
+    fn from(meta: VarMeta) -> EldocInfo {
+        let kind = if meta.special_form {
+            "special-form"
+        } else if meta.is_macro {
+            "macro"
+        } else if meta.arglists.is_some() {
+            "function"
+        } else {
+            "variable"
+        };
+
+        EldocInfo {
+            name: meta.name,
+            ns: meta.ns,
+            kind: kind.to_string(),
+            arglists: meta
+                .arglists
+                .map(|a| split_arglists(&a))
+                .unwrap_or_default(),
+            docstring: meta.doc,
+        }
+    }
+}
+
+impl nrepl::NreplOp<Option<EldocInfo>> for Eldoc {
+    type Error = StdError;
+
+    fn send(&self, n: &nrepl::NreplStream) -> Result<Option<EldocInfo>, Self::Error> {
+        let res = n.typed_op(self)?;
+
+        if res.status().contains("no-eldoc") {
+            return Ok(None);
+        }
+
+        match self.op_name() {
+            Some("eldoc") => {
+                let resp: CiderEldoc = decode("eldoc", check_status(res)?)?;
+
+                Ok(Some(EldocInfo {
+                    name: resp.name,
+                    ns: resp.ns,
+                    kind: resp.kind.unwrap_or_else(|| "function".to_string()),
+                    arglists: resp
+                        .eldoc
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|params| format!("[{}]", params.join(" ")))
+                        .collect(),
+                    docstring: resp.docstring,
+                }))
+            }
+            Some(op) => {
+                let resp: LookupResp = decode(op, check_status(res)?)?;
+
+                // Unknown symbols get empty `info`
+                match resp.info.name {
+                    Some(_) => Ok(Some(resp.info.into())),
+                    None => Ok(None),
+                }
+            }
+            None => {
+                let result = read_eval(res)?;
+
+                if let Some(ex) = result.ex {
+                    return Err(Error::BadStatus { status: ex }.into());
+                }
+
+                match result.values.last() {
+                    Some(val) => match edn::read(&mut val.as_bytes())? {
+                        Some(meta) => Ok(Some(
+                            bc::de::from_value::<VarMeta>(meta)
+                                .map_err(|err| Error::BadResponse {
+                                    op: "eval".to_string(),
+                                    err,
+                                })?
+                                .into(),
+                        )),
+                        None => Ok(None),
+                    },
+                    None => Ok(None),
+                }
             }
-            .into()),
         }
     }
 }
 
+/// Vars with names matching `query` regex, in all loaded namespaces unless `ns` is set.
+/// Needs cider-nrepl.
+pub struct Apropos {
+    session: Session,
+    query: String,
+    ns: Option<String>,
+    docs: bool,
+    privates: bool,
+    case_sensitive: bool,
+}
+
+impl Apropos {
+    pub fn new(session: Session, query: String) -> Self {
+        Self {
+            session,
+            query,
+            ns: None,
+            docs: false,
+            privates: false,
+            case_sensitive: false,
+        }
+    }
+
+    pub fn set_ns(&mut self, ns: String) {
+        self.ns = Some(ns);
+    }
+
+    /// Docstrings are searched as well
+    pub fn set_docs(&mut self, docs: bool) {
+        self.docs = docs;
+    }
+
+    pub fn set_privates(&mut self, privates: bool) {
+        self.privates = privates;
+    }
+
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool) {
+        self.case_sensitive = case_sensitive;
+    }
+}
+
+impl From<&Apropos> for nrepl::Op {
+    fn from(
+        Apropos {
+            session,
+            query,
+            ns,
+            docs,
+            privates,
+            case_sensitive,
+        }: &Apropos,
+    ) -> nrepl::Op {
+        let mut op = nrepl::Op::new("apropos")
+            .arg("query", query)
+            .opt_arg("ns", ns.as_deref())
+            .arg("session", &session.id());
+
+        // Any value is true for cider-nrepl, so flags are left out when they're off
+        let flags = [
+            ("docs?", docs),
+            ("privates?", privates),
+            ("case-sensitive?", case_sensitive),
+        ];
+        for &(flag, on) in flags.iter() {
+            if *on {
+                op = op.arg(flag, "true");
+            }
+        }
+        op
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AproposMatch {
+    /// Qualified, like `clojure.core/map`
+    pub name: String,
+    /// `function`, `macro`, `special-form`, `variable`
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub doc: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct AproposResp {
+    apropos_matches: Vec<AproposMatch>,
+}
+
+impl nrepl::NreplOp<Vec<AproposMatch>> for Apropos {
+    type Error = StdError;
+
+    fn send(&self, n: &nrepl::NreplStream) -> Result<Vec<AproposMatch>, Self::Error> {
+        if !self.session.is_op_available("apropos") {
+            return Err(Error::OpsUnavailable {
+                ops: vec!["apropos"],
+            }
+            .into());
+        }
+
+        let resps = check_status(n.typed_op(self)?)?;
+        let resp: AproposResp = decode("apropos", resps)?;
+
+        Ok(resp.apropos_matches)
+    }
+}
+
 pub struct Describe {
     verbose: bool,
 }
@@ -408,45 +993,78 @@ impl DescribeResp {
 
 impl From<&Describe> for nrepl::Op {
     fn from(Describe { verbose }: &Describe) -> nrepl::Op {
-        let mut args: Vec<(String, String)> = vec![];
+        let op = nrepl::Op::new("describe");
+
         if *verbose {
-            args.push(("verbose?".to_string(), "true".to_string()));
+            op.arg("verbose?", "true")
+        } else {
+            op
         }
-        nrepl::Op::new("describe".to_string(), args)
     }
 }
 
+#[derive(Deserialize)]
+struct DescribeFields {
+    /// Descriptions are only there with `verbose?`
+    ops: HashMap<String, serde::de::IgnoredAny>,
+}
+
 impl nrepl::NreplOp<DescribeResp> for Describe {
     type Error = StdError;
 
     fn send(&self, n: &nrepl::NreplStream) -> Result<DescribeResp, Self::Error> {
-        match n.op(self)? {
-            nrepl::Status::Done(resps) | nrepl::Status::State(resps) => {
-                let mut ops: Option<HashSet<String>> = None;
-
-                for mut resp in resps {
-                    if let Some(json_val) = resp.remove("ops") {
-                        if let BencodeValue::Dict(ops_map) = json_val {
-                            if ops.is_some() {
-                                return Err(Error::DuplicatedOpsInResponse.into());
-                            }
-                            ops = Some(
-                                ops_map
-                                    .into_iter()
-                                    .map(|(k, _)| String::from_utf8(k).map_err(|e| e.into()))
-                                    .collect::<Result<HashSet<String>, Self::Error>>()?,
-                            );
-                        }
-                    }
-                }
+        let resps = check_status(n.typed_op(self)?)?;
+        let fields: DescribeFields = decode("describe", resps)?;
 
-                Ok(DescribeResp { ops: ops.unwrap() })
-            }
+        Ok(DescribeResp {
+            ops: fields.ops.into_keys().collect(),
+        })
+    }
+}
 
-            status => Err(Error::BadStatus {
+/// Interrupts evaluation in session, the latest one unless `interrupt_id` is given
+pub struct Interrupt {
+    session: Session,
+    interrupt_id: Option<String>,
+}
+
+impl Interrupt {
+    pub fn new(session: Session, interrupt_id: Option<String>) -> Self {
+        Self {
+            session,
+            interrupt_id,
+        }
+    }
+}
+
+impl From<&Interrupt> for nrepl::Op {
+    fn from(
+        Interrupt {
+            session,
+            interrupt_id,
+        }: &Interrupt,
+    ) -> nrepl::Op {
+        nrepl::Op::new("interrupt")
+            .arg("session", &session.id())
+            .opt_arg("interrupt-id", interrupt_id.as_deref())
+    }
+}
+
+impl nrepl::NreplOp<nrepl::Status> for Interrupt {
+    type Error = StdError;
+
+    // `session-idle` and `interrupt-id-mismatch` are not failures, just nothing to interrupt
+    fn send(&self, n: &nrepl::NreplStream) -> Result<nrepl::Status, Self::Error> {
+        let res = n.typed_op(self)?;
+        let status = res.status().clone();
+
+        if status.is_error() && !status.contains("interrupt-id-mismatch") {
+            return Err(Error::BadStatus {
                 status: status.name(),
             }
-            .into()),
+            .into());
         }
+
+        Ok(status)
     }
 }
@@ -0,0 +1,180 @@
+//! Fake nrepl which answers with responses from a recorded session, so ops can be tested
+//! without a running nrepl.
+//!
+//! Sessions are recorded with `--trace` (or `UNREPL_TRACE` for the daemon), the log is used as
+//! a fixture as is. Each request gets the responses recorded for the same request, with its own
+//! `id`. When nothing like it was recorded, the next unanswered request with the same op is
+//! used, so requests which differ in details, e.g. absolute paths in code, still get answers.
+
+use crate::bencode;
+use crate::nrepl::trace::{self, Entry};
+use crate::nrepl::Error;
+use serde_bencode::value::Value as BencodeValue;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Status of the response to a request fixture knows nothing about
+pub const UNEXPECTED_STATUS: &str = "unrepl.replay/unexpected-request";
+
+type Msg = HashMap<Vec<u8>, BencodeValue>;
+
+struct Exchange {
+    /// Without `id`
+    request: Msg,
+    id: Option<BencodeValue>,
+    responses: Vec<Msg>,
+    answered: bool,
+}
+
+impl Exchange {
+    fn op(&self) -> Option<&BencodeValue> {
+        self.request.get(&b"op"[..])
+    }
+}
+
+/// Requests and their responses, in the order they were recorded
+pub struct Fixture {
+    exchanges: Vec<Exchange>,
+}
+
+impl Fixture {
+    /// Reads `--trace` log, messages of all peers are taken
+    pub fn load(path: &Path) -> Result<Fixture, Error> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut entries = vec![];
+
+        while let Some(val) = bencode::read_value(&mut r)? {
+            // Raw messages don't tell if they were sent or received
+            if let Ok(entry) = Entry::from_value(val) {
+                entries.push(entry);
+            }
+        }
+
+        Ok(Fixture::from_entries(entries))
+    }
+
+    pub fn from_entries(entries: Vec<Entry>) -> Fixture {
+        let mut exchanges: Vec<Exchange> = vec![];
+
+        for entry in entries {
+            let mut msg = match entry.msg {
+                BencodeValue::Dict(msg) => msg,
+                _ => continue,
+            };
+
+            if entry.dir == trace::SENT {
+                let id = msg.remove(&b"id"[..]);
+
+                exchanges.push(Exchange {
+                    request: msg,
+                    id,
+                    responses: vec![],
+                    answered: false,
+                });
+            } else {
+                let id = msg.get(&b"id"[..]);
+
+                if let Some(exchange) = exchanges.iter_mut().rev().find(|e| e.id.as_ref() == id) {
+                    exchange.responses.push(msg);
+                }
+            }
+        }
+
+        Fixture { exchanges }
+    }
+
+    /// Responses for `request` without `id`
+    fn answer(&mut self, request: &Msg) -> Option<Vec<Msg>> {
+        let op = request.get(&b"op"[..]);
+        let pos = self
+            .exchanges
+            .iter()
+            .position(|e| !e.answered && &e.request == request)
+            .or_else(|| {
+                self.exchanges
+                    .iter()
+                    .position(|e| !e.answered && e.op() == op)
+            })
+            // The same request could be sent more times than it was recorded
+            .or_else(|| self.exchanges.iter().position(|e| &e.request == request))?;
+
+        let exchange = &mut self.exchanges[pos];
+        exchange.answered = true;
+
+        Some(exchange.responses.clone())
+    }
+}
+
+fn unexpected_resp() -> Msg {
+    let mut resp = HashMap::new();
+
+    resp.insert(
+        b"status".to_vec(),
+        BencodeValue::List(
+            ["done", "error", UNEXPECTED_STATUS]
+                .iter()
+                .map(|s| BencodeValue::Bytes(s.as_bytes().to_vec()))
+                .collect(),
+        ),
+    );
+
+    resp
+}
+
+fn serve_client(client: TcpStream, fixture: Arc<Mutex<Fixture>>) -> Result<(), Error> {
+    let mut w = client.try_clone()?;
+    let mut r = BufReader::new(client);
+
+    while let Some(val) = bencode::read_value(&mut r)? {
+        let mut request = match val {
+            BencodeValue::Dict(request) => request,
+            _ => continue,
+        };
+        let id = request.remove(&b"id"[..]);
+        let resps = fixture
+            .lock()
+            .unwrap()
+            .answer(&request)
+            .unwrap_or_else(|| vec![unexpected_resp()]);
+
+        for mut resp in resps {
+            match &id {
+                Some(id) => resp.insert(b"id".to_vec(), id.clone()),
+                None => resp.remove(&b"id"[..]),
+            };
+
+            w.write_all(&serde_bencode::to_bytes(&BencodeValue::Dict(resp))?)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers connections accepted by `listener` until the process ends
+pub fn serve(listener: TcpListener, fixture: Fixture) -> Result<(), Error> {
+    let fixture = Arc::new(Mutex::new(fixture));
+
+    for client in listener.incoming() {
+        let client = client?;
+        let fixture = fixture.clone();
+
+        thread::spawn(move || serve_client(client, fixture));
+    }
+
+    Ok(())
+}
+
+/// Serves `fixture` in background, on a free port of localhost
+pub fn spawn(fixture: Fixture) -> Result<SocketAddr, Error> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    thread::spawn(move || serve(listener, fixture));
+
+    Ok(addr)
+}
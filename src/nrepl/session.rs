@@ -1,3 +1,5 @@
+//! Module for maintaining persistent session-id within single nrepl connection
+
 use crate::config;
 use crate::config::Session;
 use crate::nrepl;
@@ -6,8 +8,6 @@ use failure::{Error as StdError, Fail};
 use nrepl::ops::{CloneSession, Describe, LsSessions};
 use serde_bencode::value::Value as BencodeValue;
 
-///! Module for maintaining persistent session-id within single nrepl connection
-
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "io error while managing session data: {}", ioerr)]
@@ -43,11 +43,11 @@ fn create_session(nrepl: &nrepl::NreplStream) -> Result<Session, StdError> {
     ))
 }
 
-fn session_id_exists(n: &nrepl::NreplStream, session_id: &String) -> Result<bool, StdError> {
+fn session_id_exists(n: &nrepl::NreplStream, session_id: &str) -> Result<bool, StdError> {
     let op = LsSessions::new();
 
     for session in op.send(n)? {
-        if &session == session_id {
+        if session == session_id {
             return Ok(true);
         }
     }
@@ -57,6 +57,10 @@ fn session_id_exists(n: &nrepl::NreplStream, session_id: &String) -> Result<bool
 
 /// Searches for a known session in nrepl otherwise creates a new one
 pub fn get_existing_session_id(n: &nrepl::NreplStream) -> Result<Session, StdError> {
+    if let Some(relay) = n.relay() {
+        return relay.session(n);
+    }
+
     let mb_session = config::load_session(n.addr_string())?;
 
     if let Some(existing_session) = mb_session {
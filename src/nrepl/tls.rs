@@ -0,0 +1,314 @@
+//! TLS connection to nrepl, nREPL 1.0 can be started with `--tls-keys-file`.
+//!
+//! `Connection` reads from and writes to its socket from different threads, so the TLS state is
+//! shared between clones of `TlsSocket` and locked only while records are processed, never
+//! while waiting for data from the network.
+
+use super::{Error, Socket};
+use crate::config::TlsConfig;
+use rustls::{Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile::Item;
+use std::convert::TryFrom;
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn config_error(msg: String) -> Error {
+    Error::TlsConfigError { msg }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, Error> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| config_error(format!("can't open {}: {}", path.display(), e)))?;
+
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|e| config_error(format!("can't read {}: {}", path.display(), e)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let certs: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+
+    if certs.is_empty() {
+        return Err(config_error(format!(
+            "no certificates in {}",
+            path.display()
+        )));
+    }
+
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, Error> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| config_error(format!("no private key in {}", path.display())))
+}
+
+/// Trusts only the configured CA, nrepl certificates are never signed by public ones
+pub fn client_config(tls: &TlsConfig) -> Result<ClientConfig, Error> {
+    let ca_file = tls
+        .ca_file
+        .as_ref()
+        .ok_or_else(|| config_error("`tls.ca-file` is not configured".to_string()))?;
+
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(ca_file)? {
+        roots
+            .add(&cert)
+            .map_err(|e| config_error(format!("bad CA certificate: {}", e)))?;
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+
+    match (&tls.cert_file, &tls.key_file) {
+        (Some(cert_file), Some(key_file)) => builder
+            .with_client_auth_cert(read_certs(cert_file)?, read_key(key_file)?)
+            .map_err(|tls_err| Error::TlsError { tls_err }),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(config_error(
+            "`tls.cert-file` and `tls.key-file` go together".to_string(),
+        )),
+    }
+}
+
+/// TLS session over TCP, clones share it
+pub(crate) struct TlsSocket {
+    tls: Arc<Mutex<ClientConnection>>,
+    tcp: TcpStream,
+}
+
+impl TlsSocket {
+    /// Passes records which rustls wants to send, like handshake replies or alerts
+    fn flush_tls(&self, tls: &mut ClientConnection) -> std::io::Result<()> {
+        let mut tcp = &self.tcp;
+
+        while tls.wants_write() {
+            tls.write_tls(&mut tcp)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for TlsSocket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut incoming = [0u8; 16 * 1024];
+
+        loop {
+            {
+                let mut tls = self.tls.lock().unwrap();
+
+                match tls.reader().read(buf) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                    res => return res,
+                }
+            }
+
+            let n = self.tcp.read(&mut incoming)?;
+
+            if n == 0 {
+                // nrepl went away without close_notify
+                return Ok(0);
+            }
+
+            // rustls takes only part of a big read at a time
+            let mut tls = self.tls.lock().unwrap();
+            let mut data = &incoming[..n];
+            while !data.is_empty() {
+                tls.read_tls(&mut data)?;
+                tls.process_new_packets()
+                    .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+            self.flush_tls(&mut tls)?;
+        }
+    }
+}
+
+impl Write for TlsSocket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut tls = self.tls.lock().unwrap();
+        let n = tls.writer().write(buf)?;
+        self.flush_tls(&mut tls)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut tls = self.tls.lock().unwrap();
+        self.flush_tls(&mut tls)
+    }
+}
+
+impl Socket for TlsSocket {
+    fn try_clone_socket(&self) -> std::io::Result<Box<dyn Socket>> {
+        Ok(Box::new(TlsSocket {
+            tls: self.tls.clone(),
+            tcp: self.tcp.try_clone()?,
+        }))
+    }
+
+    fn shutdown_socket(&self) -> std::io::Result<()> {
+        self.tcp.shutdown(Shutdown::Both)
+    }
+
+    fn set_socket_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.tcp.set_read_timeout(timeout)
+    }
+}
+
+/// Connects and completes the handshake, so bad certificates are reported right away
+pub(crate) fn tls_connect(
+    addr: &SocketAddr,
+    host: Option<&str>,
+    tls: &TlsConfig,
+    timeout: Duration,
+) -> Result<Box<dyn Socket>, Error> {
+    // Configured name wins over the one address was given with
+    let server_name = match tls.server_name.as_deref().or(host) {
+        Some(name) => ServerName::try_from(name)
+            .map_err(|_| config_error(format!("bad tls server name: {}", name)))?,
+        None => ServerName::IpAddress(addr.ip()),
+    };
+
+    let mut conn = ClientConnection::new(Arc::new(client_config(tls)?), server_name)
+        .map_err(|tls_err| Error::TlsError { tls_err })?;
+    let mut tcp = super::tcp_connect_stream(addr, timeout)?;
+
+    tcp.set_read_timeout(Some(timeout))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp).map_err(|e| match e.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Error::ConnectTimeout {
+                addr: addr.to_string(),
+                timeout,
+            },
+            _ => e.into(),
+        })?;
+    }
+    tcp.set_read_timeout(None)?;
+
+    Ok(Box::new(TlsSocket {
+        tls: Arc::new(Mutex::new(conn)),
+        tcp,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nrepl::{read_resp, Addr, NreplStream, Op};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+    use rustls::server::AllowAnyAuthenticatedClient;
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use serde_bencode::value::Value as BencodeValue;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn write_pem(dir: &Path, name: &str, pem: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+        path
+    }
+
+    #[test]
+    fn tls_stream_with_client_cert_test() {
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let server =
+            rcgen::Certificate::from_params(CertificateParams::new(vec!["127.0.0.1".to_string()]))
+                .unwrap();
+        let client =
+            rcgen::Certificate::from_params(CertificateParams::new(vec!["unrepl".to_string()]))
+                .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("unrepl-tls-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_pem = ca.serialize_pem().unwrap();
+        let server_pem = server.serialize_pem_with_signer(&ca).unwrap();
+        let server_key_pem = server.serialize_private_key_pem();
+        let client_pem = client.serialize_pem_with_signer(&ca).unwrap();
+        let client_key_pem = client.serialize_private_key_pem();
+
+        let tls = TlsConfig {
+            ca_file: Some(write_pem(&dir, "ca.pem", &ca_pem)),
+            cert_file: Some(write_pem(&dir, "client.pem", &client_pem)),
+            key_file: Some(write_pem(&dir, "client-key.pem", &client_key_pem)),
+            server_name: None,
+        };
+        let server_certs = read_certs(&write_pem(&dir, "server.pem", &server_pem)).unwrap();
+        let server_key = read_key(&write_pem(&dir, "server-key.pem", &server_key_pem)).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&read_certs(tls.ca_file.as_ref().unwrap()).unwrap()[0])
+            .unwrap();
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            .with_single_cert(server_certs, server_key)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Echoes op name back as `value`, `out` spans several TLS records
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let conn = ServerConnection::new(Arc::new(server_config)).unwrap();
+            let mut stream = BufReader::new(StreamOwned::new(conn, tcp));
+            let mut req = read_resp(&mut stream).unwrap();
+
+            let mut resp: HashMap<&str, BencodeValue> = HashMap::new();
+            resp.insert("id", req.remove("id").unwrap());
+            resp.insert("value", req.remove("op").unwrap());
+            resp.insert("out", BencodeValue::Bytes(vec![b'x'; 40 * 1024]));
+            resp.insert(
+                "status",
+                BencodeValue::List(vec![BencodeValue::Bytes(b"done".to_vec())]),
+            );
+            let mut stream = stream.into_inner();
+            stream
+                .write_all(&serde_bencode::to_bytes(&resp).unwrap())
+                .unwrap();
+            stream.flush().unwrap();
+        });
+
+        let mut n = NreplStream::persistent(&Addr::Tls {
+            addr,
+            server_name: None,
+        })
+        .unwrap();
+        n.set_tls(tls);
+
+        let res = n.op(Op::new("describe")).unwrap();
+        assert!(res.status().is_done());
+
+        let mut resp = res.into_resps().pop().unwrap();
+        let value = resp.remove("value").unwrap();
+        assert_eq!(crate::bencode::try_into_string(value).unwrap(), "describe");
+        let out = resp.remove("out").unwrap();
+        assert_eq!(
+            crate::bencode::try_into_string(out).unwrap(),
+            "x".repeat(40 * 1024)
+        );
+
+        server.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
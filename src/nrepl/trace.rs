@@ -0,0 +1,189 @@
+//! Log of every message sent to and received from nrepl, for when it's not clear what nrepl
+//! has actually answered.
+//!
+//! Log is a sequence of bencode dicts like `{"time": 1584000000000, "dir": "sent", "peer":
+//! "127.0.0.1:7888", "msg": {...}}`, one for each message, whatever transport nrepl talks.
+//! `unrepl decode` shows it in readable form.
+
+use crate::nrepl::{Error, Op, Resp};
+use serde_bencode::value::Value as BencodeValue;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable with path of the log, used when there's no `--trace` option
+pub const TRACE_ENV: &str = "UNREPL_TRACE";
+
+pub const SENT: &str = "sent";
+pub const RECEIVED: &str = "received";
+
+/// Appends messages to the log file, clones share the same file
+#[derive(Clone)]
+pub struct Trace {
+    file: Arc<Mutex<File>>,
+    peer: String,
+}
+
+impl Trace {
+    pub fn open(path: &Path) -> Result<Trace, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Trace {
+            file: Arc::new(Mutex::new(file)),
+            peer: String::new(),
+        })
+    }
+
+    /// Opens log at `UNREPL_TRACE`, if it's set
+    pub fn from_env() -> Result<Option<Trace>, Error> {
+        match std::env::var_os(TRACE_ENV) {
+            Some(path) if !path.is_empty() => Ok(Some(Trace::open(Path::new(&path))?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Same log, entries are marked as messages of `peer`
+    pub(crate) fn to_peer(&self, peer: String) -> Trace {
+        Trace {
+            file: self.file.clone(),
+            peer,
+        }
+    }
+
+    fn write(&self, dir: &str, msg: BencodeValue) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default();
+
+        let mut entry = HashMap::new();
+        entry.insert("time", BencodeValue::Int(time));
+        entry.insert("dir", BencodeValue::Bytes(dir.as_bytes().to_vec()));
+        entry.insert("peer", BencodeValue::Bytes(self.peer.as_bytes().to_vec()));
+        entry.insert("msg", msg);
+
+        // Broken log must not break ops
+        if let Ok(bs) = serde_bencode::to_bytes(&entry) {
+            let _ = self.file.lock().unwrap().write_all(&bs);
+        }
+    }
+
+    pub(crate) fn sent(&self, op: &Op) {
+        let msg = serde_bencode::to_bytes(op).and_then(|bs| serde_bencode::from_bytes(&bs));
+
+        if let Ok(msg) = msg {
+            self.write(SENT, msg);
+        }
+    }
+
+    pub(crate) fn received(&self, resp: &Resp) {
+        let msg = serde_bencode::to_bytes(resp).and_then(|bs| serde_bencode::from_bytes(&bs));
+
+        if let Ok(msg) = msg {
+            self.write(RECEIVED, msg);
+        }
+    }
+}
+
+/// Single log entry
+pub struct Entry {
+    /// Milliseconds since unix epoch
+    pub time: i64,
+    /// `SENT` or `RECEIVED`
+    pub dir: String,
+    pub peer: String,
+    pub msg: BencodeValue,
+}
+
+impl Entry {
+    /// Gives `val` back if it's not a log entry, e.g. a message from raw capture of nrepl traffic
+    pub fn from_value(val: BencodeValue) -> Result<Entry, BencodeValue> {
+        let mut map = match val {
+            BencodeValue::Dict(map) => map,
+            val => return Err(val),
+        };
+
+        match (
+            map.remove(&b"time"[..]),
+            map.remove(&b"dir"[..]),
+            map.remove(&b"peer"[..]),
+            map.remove(&b"msg"[..]),
+        ) {
+            (
+                Some(BencodeValue::Int(time)),
+                Some(BencodeValue::Bytes(dir)),
+                Some(BencodeValue::Bytes(peer)),
+                Some(msg),
+            ) if map.is_empty() => Ok(Entry {
+                time,
+                dir: String::from_utf8_lossy(&dir).into_owned(),
+                peer: String::from_utf8_lossy(&peer).into_owned(),
+                msg,
+            }),
+            (time, dir, peer, msg) => {
+                let fields = vec![("time", time), ("dir", dir), ("peer", peer), ("msg", msg)];
+
+                for (k, v) in fields {
+                    if let Some(v) = v {
+                        map.insert(k.as_bytes().to_vec(), v);
+                    }
+                }
+                Err(BencodeValue::Dict(map))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode;
+    use std::io::BufReader;
+
+    #[test]
+    fn traced_messages_are_read_back_test() {
+        let path = std::env::temp_dir().join(format!("unrepl-trace-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let trace = Trace::open(&path)
+            .unwrap()
+            .to_peer("127.0.0.1:7888".to_string());
+        let mut op = Op::new("eval").arg("code", "1");
+        op.set_id("1".to_string());
+        trace.sent(&op);
+
+        let mut resp = HashMap::new();
+        resp.insert("id".to_string(), BencodeValue::Bytes(b"1".to_vec()));
+        resp.insert("value".to_string(), BencodeValue::Bytes(b"1".to_vec()));
+        trace.received(&Resp::from(resp));
+
+        let mut r = BufReader::new(File::open(&path).unwrap());
+        let mut entries = vec![];
+        while let Some(val) = bencode::read_value(&mut r).unwrap() {
+            entries.push(Entry::from_value(val).ok().unwrap());
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].dir, SENT);
+        assert_eq!(entries[1].dir, RECEIVED);
+        assert_eq!(entries[1].peer, "127.0.0.1:7888");
+        match &entries[0].msg {
+            BencodeValue::Dict(msg) => {
+                assert_eq!(msg[&b"op"[..]], BencodeValue::Bytes(b"eval".to_vec()))
+            }
+            msg => panic!("expected dict, got: {:?}", msg),
+        }
+
+        // Raw nrepl message is not an entry
+        let raw = BencodeValue::Dict(
+            vec![(b"op".to_vec(), BencodeValue::Bytes(b"describe".to_vec()))]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(Entry::from_value(raw.clone()).err(), Some(raw));
+    }
+}
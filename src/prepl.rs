@@ -0,0 +1,310 @@
+//! Client of `clojure.core.server/io-prepl`, for processes which don't run nrepl.
+//!
+//! prepl reads plain forms and answers with EDN maps like `{:tag :ret, :val "1", :ns "user"}`,
+//! preceded by `:out` and `:err` ones for everything evaluation has printed. There are no
+//! sessions or middleware, so `info` and friends are implemented by evaluating clojure code.
+
+use crate::backend::{Backend, EvalResult, Source};
+use crate::bencode as bc;
+use crate::config::TlsConfig;
+use crate::edn;
+use crate::nrepl;
+use crate::nrepl::ops::{self, clj_str};
+use failure::{Error as StdError, Fail};
+use serde_bencode::value::Value as BencodeValue;
+use std::convert::TryFrom;
+use std::io::{BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "prepl closed connection")]
+    Closed,
+    #[fail(display = "prepl sent nothing in {:?}", timeout)]
+    Timeout { timeout: Duration },
+    #[fail(display = "prepl sent unexpected message: {:?}", msg)]
+    BadMessage { msg: Option<BencodeValue> },
+}
+
+struct Connection {
+    r: BufReader<Box<dyn nrepl::Socket>>,
+    w: Box<dyn nrepl::Socket>,
+}
+
+/// Single prepl connection, forms are evaluated one after another
+pub struct PreplStream {
+    addr: nrepl::Addr,
+    timeouts: nrepl::Timeouts,
+    tls: TlsConfig,
+    conn: Mutex<Option<Connection>>,
+}
+
+impl PreplStream {
+    pub fn new(addr: &nrepl::Addr) -> PreplStream {
+        PreplStream {
+            addr: addr.clone(),
+            timeouts: nrepl::Timeouts::default(),
+            tls: TlsConfig::default(),
+            conn: Mutex::new(None),
+        }
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: nrepl::Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn set_tls(&mut self, tls: TlsConfig) {
+        self.tls = tls;
+    }
+
+    fn connect(&self) -> Result<Connection, StdError> {
+        let w = self.addr.connect(self.timeouts.connect, &self.tls)?;
+        w.set_socket_read_timeout(self.timeouts.idle)?;
+        let r = BufReader::new(w.try_clone_socket()?);
+
+        Ok(Connection { r, w })
+    }
+
+    fn read_msg(&self, conn: &mut Connection) -> Result<nrepl::Resp, StdError> {
+        match edn::read(&mut conn.r) {
+            Ok(Some(msg)) => nrepl::Resp::try_from(msg.clone())
+                .map_err(|_| Error::BadMessage { msg: Some(msg) }.into()),
+            Ok(None) => Err(Error::BadMessage { msg: None }.into()),
+            Err(edn::Error::IOError { ioerr }) => Err(match ioerr.kind() {
+                ErrorKind::UnexpectedEof => Error::Closed.into(),
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => Error::Timeout {
+                    timeout: self.timeouts.idle.unwrap_or_default(),
+                }
+                .into(),
+                _ => ioerr.into(),
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends single `form` and collects messages until its `:ret`
+    pub fn eval_form(&self, form: &str) -> Result<EvalResult, StdError> {
+        let mut conn = self.conn.lock().unwrap();
+
+        if conn.is_none() {
+            *conn = Some(self.connect()?);
+        }
+        let c = conn.as_mut().unwrap();
+
+        if let Err(e) = c.w.write_all(format!("{}\n", form).as_bytes()) {
+            *conn = None;
+            return Err(e.into());
+        }
+
+        let mut result = EvalResult::default();
+
+        loop {
+            let mut msg = match self.read_msg(c) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    // Whatever comes next belongs to this form
+                    *conn = None;
+                    return Err(e);
+                }
+            };
+            let tag = msg.remove("tag").map(bc::try_into_string).transpose()?;
+            let val = msg
+                .remove("val")
+                .map(bc::try_into_string)
+                .transpose()?
+                .unwrap_or_default();
+
+            match tag.as_deref() {
+                Some("out") => result.out.push_str(&val),
+                Some("err") => result.err.push_str(&val),
+                Some("ret") => {
+                    if msg.contains_key("exception") {
+                        result.ex = Some(val);
+                    } else {
+                        result.values.push(val);
+                    }
+                    return Ok(result);
+                }
+                // `:tap` and whatever else could be added later
+                _ => (),
+            }
+        }
+    }
+
+    /// Evaluates `form` which must not throw, its printed value is read as EDN
+    fn eval_edn(&self, form: &str) -> Result<Option<BencodeValue>, StdError> {
+        let result = self.eval_form(form)?;
+
+        if let Some(ex) = result.ex {
+            return Err(ops::Error::BadStatus { status: ex }.into());
+        }
+
+        match result.values.last() {
+            Some(val) => Ok(edn::read(&mut val.as_bytes())?),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Backend for PreplStream {
+    fn eval(&self, code: &str, ns: Option<&str>, source: &Source) -> Result<EvalResult, StdError> {
+        // Same as `load-string`, but knows where the code comes from
+        let load = format!(
+            "(clojure.lang.Compiler/load
+               (clojure.core/doto (clojure.lang.LineNumberingPushbackReader.
+                                    (java.io.StringReader. {code}))
+                 (.setLineNumber {line}))
+               {path} {name})",
+            code = clj_str(code),
+            line = source.line.unwrap_or(1),
+            path = clj_str(source.file.as_deref().unwrap_or("NO_SOURCE_PATH")),
+            name = clj_str(
+                source
+                    .file
+                    .as_deref()
+                    .and_then(|f| Path::new(f).file_name())
+                    .and_then(|f| f.to_str())
+                    .unwrap_or("NO_SOURCE_FILE")
+            ),
+        );
+
+        // Single form, so there's single `:ret`
+        let form = match ns {
+            Some(ns) => format!(
+                "(clojure.core/binding [clojure.core/*ns* \
+                                        (clojure.core/the-ns (clojure.core/symbol {}))] \
+                 {})",
+                clj_str(ns),
+                load
+            ),
+            None => load,
+        };
+
+        self.eval_form(&form)
+    }
+
+    fn ns_name(&self, file: &str) -> Result<Option<String>, StdError> {
+        let form = format!(
+            "(clojure.core/with-open [r (java.io.PushbackReader. (clojure.java.io/reader {}))]
+               (clojure.core/loop []
+                 (clojure.core/let [form (clojure.core/read {{:eof ::eof :read-cond :allow}} r)]
+                   (clojure.core/cond
+                     (clojure.core/= form ::eof) nil
+                     (clojure.core/and (clojure.core/seq? form)
+                                       (clojure.core/= 'ns (clojure.core/first form)))
+                     (clojure.core/str (clojure.core/second form))
+                     :else (recur)))))",
+            clj_str(file)
+        );
+
+        match self.eval_edn(&form)? {
+            Some(ns) => Ok(Some(bc::try_into_string(ns)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn info(&self, ns: &str, symbol: &str) -> Result<Option<ops::InfoResponseType>, StdError> {
+        // Produces the same fields as cider-nrepl's `info`
+        let form = format!(
+            "(clojure.core/let [ns (clojure.core/the-ns '{ns})
+                   sym '{symbol}
+                   v (clojure.core/ns-resolve ns sym)
+                   found-ns (clojure.core/or (clojure.core/find-ns sym)
+                                             (clojure.core/get (clojure.core/ns-aliases ns) sym))
+                   url (clojure.core/fn [file]
+                         (clojure.core/when file
+                           (clojure.core/or
+                             (clojure.java.io/resource file)
+                             (clojure.core/let [f (java.io.File. file)]
+                               (clojure.core/when (.exists f) (.toURL (.toURI f)))))))]
+               (clojure.core/cond
+                 (clojure.core/var? v)
+                 (clojure.core/let [m (clojure.core/meta v)]
+                   (clojure.core/when-let [u (url (:file m))]
+                     {{:file (clojure.core/str u)
+                      :resource (:file m)
+                      :line (:line m)
+                      :column (:column m)
+                      :ns (clojure.core/str (clojure.core/ns-name (:ns m)))
+                      :name (clojure.core/str (:name m))
+                      :doc (:doc m)
+                      :arglists-str (clojure.core/some->> (:arglists m)
+                                      (clojure.core/map clojure.core/pr-str)
+                                      (clojure.string/join \"\\n\"))
+                      :macro (clojure.core/when (:macro m) \"true\")}}))
+
+                 found-ns
+                 (clojure.core/let [m (clojure.core/some-> found-ns clojure.core/ns-interns
+                                        clojure.core/vals clojure.core/first clojure.core/meta)]
+                   (clojure.core/when-let [u (url (:file m))]
+                     {{:file (clojure.core/str u)
+                      :resource (:file m)
+                      :line 1
+                      :ns (clojure.core/str (clojure.core/ns-name found-ns))
+                      :doc (:doc (clojure.core/meta found-ns))}}))))",
+            ns = ns,
+            symbol = symbol
+        );
+
+        match self.eval_edn(&form)? {
+            Some(val) => ops::parse_info(nrepl::Resp::try_from(val).map_err(nrepl::Error::from)?),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn eval_and_info_over_prepl_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Skips forms it receives and sends prepared messages for each of them
+        let server = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut r = std::io::BufReader::new(tcp.try_clone().unwrap());
+            let mut w = tcp;
+            let answers = [
+                r#"{:tag :out, :val "hi\n"} {:tag :ret, :val "3", :ns "user", :ms 1}"#,
+                r#"{:tag :ret, :val "{:file \"file:/src/my/ns.clj\", :resource \"my/ns.clj\",
+                    :line 10, :column 1, :ns \"my.ns\", :name \"f\", :doc \"Does f\",
+                    :arglists-str \"[x]\", :macro nil}", :ns "user", :ms 2}"#,
+            ];
+
+            for answer in answers.iter() {
+                edn::read(&mut r).unwrap();
+                w.write_all(answer.as_bytes()).unwrap();
+            }
+        });
+
+        let prepl = PreplStream::new(&addr.into());
+
+        let res = prepl
+            .eval(
+                "(println \"hi\") (+ 1 2)",
+                Some("my.ns"),
+                &Source::default(),
+            )
+            .unwrap();
+        assert_eq!(res.out, "hi\n");
+        assert_eq!(res.values, vec!["3".to_string()]);
+        assert!(res.ex.is_none());
+
+        let info = match prepl.info("my.ns", "f").unwrap() {
+            Some(ops::InfoResponseType::Symbol(info)) => info,
+            _ => panic!("expected symbol info"),
+        };
+        assert_eq!(info.line, 10);
+        assert_eq!(info.file, "file:/src/my/ns.clj");
+        assert_eq!(info.doc, "my.ns/f\n([x])\nDoes f");
+
+        server.join().unwrap();
+    }
+}
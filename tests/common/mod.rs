@@ -0,0 +1,17 @@
+use std::path::PathBuf;
+use std::sync::Once;
+
+static SETUP: Once = Once::new();
+
+pub fn data_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("unrepl-test-{}", std::process::id()))
+}
+
+/// Sessions database is kept away from the real one
+pub fn setup() {
+    SETUP.call_once(|| {
+        std::env::set_var("XDG_DATA_HOME", data_dir());
+        unrepl::config::ensure_config_dir().unwrap();
+        unrepl::config::ensure_migrations().unwrap();
+    });
+}
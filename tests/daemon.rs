@@ -0,0 +1,181 @@
+//! `unrepl daemon` in front of mock nrepl, talked to the way CLI does
+
+mod common;
+
+use serde_bencode::value::Value as BencodeValue;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use unrepl::bencode;
+use unrepl::daemon::{self, ReadJar};
+use unrepl::nrepl::mock::{MockNrepl, MockServer};
+use unrepl::nrepl::{session, Addr, Error, NreplOp, NreplStream, Op, Resp};
+
+/// Starts daemon on a socket of its own, named after the test
+fn start_daemon(name: &str) -> PathBuf {
+    common::setup();
+
+    let path = common::data_dir().join(format!("{}.sock", name));
+    let serve_path = path.clone();
+    thread::spawn(move || daemon::serve(&serve_path).unwrap());
+
+    for _ in 0..100 {
+        if UnixStream::connect(&path).is_ok() {
+            return path;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("daemon didn't start at {:?}", path);
+}
+
+fn via_daemon(addr: &Addr, socket: &Path) -> NreplStream {
+    daemon::connect(addr, socket).unwrap()
+}
+
+fn str_field(resp: &Resp, key: &str) -> Option<String> {
+    resp.get(key)
+        .and_then(|v| bencode::try_into_string(v.clone()).ok())
+}
+
+fn requests_of(server: &MockServer, op: &str) -> Vec<Resp> {
+    server
+        .requests()
+        .into_iter()
+        .filter(|req| str_field(req, "op").as_deref() == Some(op))
+        .collect()
+}
+
+#[test]
+fn op_is_forwarded_as_it_is_test() {
+    let server = MockNrepl::new().start().unwrap();
+    let n = via_daemon(server.addr(), &start_daemon("forward"));
+
+    let mut op = Op::new("eval").arg("code", "(+ 1 2)").int("line", 10);
+    op.set_id("client-1".to_string());
+    let resps = n.op(op).unwrap().into_resps();
+
+    assert_eq!(str_field(&resps[0], "id").as_deref(), Some("client-1"));
+    assert_eq!(str_field(&resps[0], "value").as_deref(), Some("nil"));
+
+    let reqs = requests_of(&server, "eval");
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(str_field(&reqs[0], "id").as_deref(), Some("client-1"));
+    assert_eq!(reqs[0].get("line"), Some(&BencodeValue::Int(10)));
+    // Meant for the daemon only
+    assert!(!reqs[0].contains_key(daemon::ADDR_ARG));
+    assert!(!reqs[0].contains_key(daemon::CODEC_ARG));
+}
+
+#[test]
+fn session_is_cached_until_nrepl_forgets_it_test() {
+    let server = MockNrepl::new().start().unwrap();
+    let n = via_daemon(server.addr(), &start_daemon("session"));
+
+    let first = session::get_existing_session_id(&n).unwrap();
+    let again = session::get_existing_session_id(&n).unwrap();
+    assert_eq!(first.id(), again.id());
+    assert!(first.is_op_available("eval"));
+    assert_eq!(requests_of(&server, "clone").len(), 1);
+    assert!(requests_of(&server, "ls-sessions").is_empty());
+
+    // Like nrepl was restarted, daemon learns about it from a forwarded op
+    server.forget_sessions();
+    let res = n.op(Op::new("eval").arg("session", &first.id())).unwrap();
+    assert!(res.status().is_unknown_session());
+
+    let renewed = session::get_existing_session_id(&n).unwrap();
+    assert_ne!(renewed.id(), first.id());
+    assert_eq!(requests_of(&server, "ls-sessions").len(), 1);
+    assert_eq!(requests_of(&server, "clone").len(), 2);
+}
+
+#[test]
+fn read_jar_test() {
+    common::setup();
+
+    let jar = common::data_dir().join("app.jar");
+    let mut zip = zip::ZipWriter::new(File::create(&jar).unwrap());
+    zip.start_file("my/app.clj", zip::write::FileOptions::default())
+        .unwrap();
+    zip.write_all(b"(ns my.app)\n").unwrap();
+    zip.finish().unwrap();
+
+    let n = via_daemon(&"127.0.0.1:1".parse().unwrap(), &start_daemon("read-jar"));
+    let jar = jar.to_string_lossy().into_owned();
+
+    let contents = ReadJar::new(jar.clone(), "my/app.clj".to_string())
+        .send(&n)
+        .unwrap();
+    assert_eq!(contents, "(ns my.app)\n");
+
+    let err = ReadJar::new(jar, "my/missing.clj".to_string())
+        .send(&n)
+        .unwrap_err();
+    assert!(err.to_string().starts_with("unrepl daemon failed:"));
+}
+
+#[test]
+fn daemon_error_is_returned_to_client_test() {
+    // Daemon only takes absolute socket paths
+    let addr = Addr::Unix(PathBuf::from("nrepl.sock"));
+    let n = via_daemon(&addr, &start_daemon("bad-addr"));
+
+    match n.op(Op::new("eval").arg("code", "1")) {
+        Err(Error::DaemonError { msg }) => assert_eq!(msg, "bad nrepl address: nrepl.sock"),
+        res => panic!("expected daemon error, got: {:?}", res),
+    }
+}
+
+#[test]
+fn cli_falls_back_to_direct_mode_test() {
+    common::setup();
+    let server = MockNrepl::new().start().unwrap();
+
+    // Stale socket of a daemon which wasn't stopped cleanly, nothing listens on it
+    let data_dir = common::data_dir().join("no-daemon");
+    let socket = data_dir.join("unrepl").join("daemon.sock");
+    std::fs::create_dir_all(socket.parent().unwrap()).unwrap();
+    let _ = std::fs::remove_file(&socket);
+    drop(UnixListener::bind(&socket).unwrap());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_unrepl"))
+        .env("XDG_DATA_HOME", &data_dir)
+        .args(["-p", &server.addr().to_string(), "eval", "(+ 1 2)"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "nil\n");
+
+    // Came straight from CLI, not forwarded by a daemon
+    let reqs = requests_of(&server, "eval");
+    assert_eq!(reqs.len(), 1);
+    assert!(!reqs[0].contains_key(daemon::ADDR_ARG));
+}
+
+#[test]
+fn stale_socket_is_replaced_by_daemon_test() {
+    common::setup();
+
+    let path = daemon::socket_path();
+    let _ = std::fs::remove_file(&path);
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists() && !daemon::is_running());
+
+    thread::spawn(move || daemon::serve(&path).unwrap());
+    for _ in 0..100 {
+        if daemon::is_running() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(daemon::is_running());
+
+    match daemon::serve(&daemon::socket_path()) {
+        Err(e) => assert!(e.to_string().starts_with("daemon is already running")),
+        Ok(()) => panic!("second daemon started"),
+    }
+}
@@ -0,0 +1,591 @@
+//! How `NreplStream` copes with slow, chatty and broken nrepl
+
+mod common;
+
+use serde_bencode::value::Value as BencodeValue;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
+use unrepl::backend::{Backend, Source};
+use unrepl::nrepl::mock::{MockNrepl, MockServer, Reply};
+use unrepl::nrepl::ops::{Complete, Describe, Eldoc};
+use unrepl::nrepl::{session, Error, NreplOp, NreplStream, Op, RespError, Timeouts};
+
+fn nrepl(server: &MockServer) -> NreplStream {
+    common::setup();
+
+    let mut n = NreplStream::persistent(server.addr()).unwrap();
+    n.set_timeouts(Timeouts {
+        idle: Some(Duration::from_millis(300)),
+        ..Timeouts::default()
+    });
+    n
+}
+
+/// Runs CLI against `server` with `stdin` as its input
+fn unrepl(server: &MockServer, args: &[&str], stdin: &str) -> Output {
+    common::setup();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_unrepl"))
+        .env("XDG_DATA_HOME", common::data_dir())
+        .args(["-p", &server.addr().to_string()])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+
+    child.wait_with_output().unwrap()
+}
+
+fn eval_op(code: &str) -> Op {
+    Op::new("eval").arg("code", code)
+}
+
+#[test]
+fn multi_message_response_test() {
+    let server = MockNrepl::new()
+        .eval(
+            "(do (println \"hi\") (binding [*out* *err*] (println \"oops\")) 42)",
+            Reply::new().out("hi\n").err("oops\n").value("42").done(),
+        )
+        .start()
+        .unwrap();
+    let n = nrepl(&server);
+
+    let res = n
+        .eval(
+            "(do (println \"hi\") (binding [*out* *err*] (println \"oops\")) 42)",
+            None,
+            &Source::default(),
+        )
+        .unwrap();
+    assert_eq!(res.out, "hi\n");
+    assert_eq!(res.err, "oops\n");
+    assert_eq!(res.values, vec!["42".to_string()]);
+    assert!(res.ex.is_none());
+}
+
+#[test]
+fn thrown_exception_test() {
+    let server = MockNrepl::new()
+        .eval(
+            "(/ 1 0)",
+            Reply::new()
+                .err("Execution error (ArithmeticException)")
+                .ex("class java.lang.ArithmeticException")
+                .done(),
+        )
+        .start()
+        .unwrap();
+
+    let res = nrepl(&server)
+        .eval("(/ 1 0)", None, &Source::default())
+        .unwrap();
+    assert_eq!(
+        res.ex,
+        Some("class java.lang.ArithmeticException".to_string())
+    );
+}
+
+#[test]
+fn split_message_test() {
+    let server = MockNrepl::new()
+        .eval(
+            "(range 3)",
+            Reply::new()
+                .split(Duration::from_millis(100))
+                .value("(0 1 2)")
+                .done(),
+        )
+        .start()
+        .unwrap();
+
+    let res = nrepl(&server).op(eval_op("(range 3)")).unwrap();
+    assert!(res.status().is_done());
+    assert_eq!(res.resps().len(), 2);
+}
+
+#[test]
+fn idle_timeout_test() {
+    let server = MockNrepl::new()
+        .eval(
+            "(Thread/sleep 1000)",
+            Reply::new()
+                .delay(Duration::from_secs(1))
+                .value("nil")
+                .done(),
+        )
+        .start()
+        .unwrap();
+    let n = nrepl(&server);
+
+    match n.op(eval_op("(Thread/sleep 1000)")) {
+        Err(Error::IdleTimeout { .. }) => (),
+        res => panic!("expected idle timeout, got: {:?}", res),
+    }
+
+    // Late responses of the timed out op don't confuse the next one
+    let res = n.op(eval_op("(+ 1 2)")).unwrap();
+    assert!(res.status().is_done());
+}
+
+#[test]
+fn streaming_keeps_op_alive_test() {
+    // Each message comes before idle timeout, all of them don't
+    let mut reply = Reply::new();
+    for i in 0..5 {
+        reply = reply.delay(Duration::from_millis(100)).out(&i.to_string());
+    }
+    let server = MockNrepl::new()
+        .eval("(dotimes [i 5] (Thread/sleep 100) (print i))", reply.done())
+        .start()
+        .unwrap();
+
+    let res = nrepl(&server)
+        .op(eval_op("(dotimes [i 5] (Thread/sleep 100) (print i))"))
+        .unwrap();
+    assert_eq!(res.resps().len(), 6);
+}
+
+#[test]
+fn id_of_op_in_flight_is_refused_test() {
+    let server = MockNrepl::new()
+        .eval(
+            "(Thread/sleep 200)",
+            Reply::new()
+                .delay(Duration::from_millis(200))
+                .value("nil")
+                .done(),
+        )
+        .start()
+        .unwrap();
+    let n = nrepl(&server);
+    let with_id = |code: &str| {
+        let mut op = eval_op(code);
+        op.set_id("user-1".to_string());
+        op
+    };
+
+    let slow = n.send(with_id("(Thread/sleep 200)")).unwrap();
+    match n.send(with_id("(+ 1 2)")).err() {
+        Some(Error::DuplicateId { id }) => assert_eq!(id, "user-1"),
+        res => panic!("expected duplicate id error, got: {:?}", res),
+    }
+    assert!(slow.wait().unwrap().status().is_done());
+
+    // Free again once its op is done
+    assert!(n.op(with_id("(+ 1 2)")).unwrap().status().is_done());
+}
+
+#[test]
+fn closed_in_the_middle_of_message_test() {
+    let server = MockNrepl::new()
+        .eval(
+            "(System/exit 0)",
+            Reply::new().out("bye\n").close().value("nil"),
+        )
+        .start()
+        .unwrap();
+
+    match nrepl(&server).op(eval_op("(System/exit 0)")) {
+        Err(Error::ServerClosed { .. }) => (),
+        res => panic!("expected closed connection, got: {:?}", res),
+    }
+}
+
+#[test]
+fn bad_message_fails_only_its_op_test() {
+    let server = MockNrepl::new()
+        .eval(
+            "(Thread/sleep 100)",
+            Reply::new()
+                .delay(Duration::from_millis(100))
+                .value("nil")
+                .done(),
+        )
+        .on(
+            "broken",
+            Reply::new()
+                .raw_msg(vec![(b"\xffout", BencodeValue::Bytes(b"hi".to_vec()))])
+                .done(),
+        )
+        .start()
+        .unwrap();
+    let n = nrepl(&server);
+
+    let slow = n.send(eval_op("(Thread/sleep 100)")).unwrap();
+
+    match n.op(Op::new("broken")) {
+        Err(Error::BencodeFormatError(RespError::BadUtf8(_))) => (),
+        res => panic!("expected bad utf-8 key, got: {:?}", res),
+    }
+
+    // Connection is still there for the op in flight and the next ones
+    assert!(slow.wait().unwrap().status().is_done());
+    assert!(n.op(eval_op("(+ 1 2)")).unwrap().status().is_done());
+}
+
+#[test]
+fn unknown_session_recovery_test() {
+    let server = MockNrepl::new().start().unwrap();
+    let n = nrepl(&server);
+
+    let old = session::get_existing_session_id(&n).unwrap();
+    server.forget_sessions();
+
+    let res = n
+        .op(Op::new("eval").arg("code", "1").arg("session", &old.id()))
+        .unwrap();
+    assert!(res.status().is_unknown_session());
+
+    // Stale session from the database is noticed and replaced
+    let new = session::get_existing_session_id(&n).unwrap();
+    assert_ne!(new.id(), old.id());
+    assert_eq!(
+        n.eval("1", None, &Source::default()).unwrap().values,
+        vec!["nil".to_string()]
+    );
+}
+
+#[test]
+fn unix_socket_test() {
+    let path = std::env::temp_dir().join(format!("unrepl-mock-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let server = MockNrepl::new()
+        .on("complete", Reply::new().done())
+        .start_unix(&path)
+        .unwrap();
+
+    let ops = Describe::new(false)
+        .send(&nrepl(&server))
+        .unwrap()
+        .into_ops();
+    assert!(ops.contains("complete"));
+    assert!(ops.contains("eval"));
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        unrepl::bencode::try_into_string(requests[0]["op"].clone()).unwrap(),
+        "describe"
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn eval_command_test() {
+    let server = MockNrepl::new()
+        .eval(
+            "(do (println \"hi\") [1 2])",
+            Reply::new().out("hi\n").value("[1 2]").done(),
+        )
+        .eval(
+            "(/ 1 0)",
+            Reply::new()
+                .err("Execution error (ArithmeticException)\n")
+                .ex("class java.lang.ArithmeticException")
+                .done(),
+        )
+        .start()
+        .unwrap();
+
+    // Code with `=` in it, which `op eval code=...` can't send
+    let output = unrepl(&server, &["eval", "-n", "user", "(= 1 1)"], "");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "nil\n");
+
+    let output = unrepl(&server, &["eval", "--json"], "(do (println \"hi\") [1 2])");
+    assert!(output.status.success(), "{:?}", output);
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["values"], serde_json::json!(["[1 2]"]));
+    assert_eq!(json["out"], "hi\n");
+    assert_eq!(json["ns"], "user");
+
+    let output = unrepl(&server, &["eval", "(/ 1 0)"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Execution error (ArithmeticException)\n"
+    );
+
+    let evals: Vec<_> = server
+        .requests()
+        .into_iter()
+        .filter(|req| unrepl::bencode::try_into_string(req["op"].clone()).unwrap() == "eval")
+        .collect();
+    assert_eq!(evals.len(), 3);
+    assert_eq!(
+        unrepl::bencode::try_into_string(evals[0]["ns"].clone()).unwrap(),
+        "user"
+    );
+}
+
+#[test]
+fn load_command_test() {
+    let dir = common::data_dir();
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("broken.clj");
+    std::fs::write(&path, "(ns broken)\n\n(defn f [] (foo))\n").unwrap();
+    let path = path.canonicalize().unwrap();
+
+    let server = MockNrepl::new()
+        .on(
+            "load-file",
+            Reply::new()
+                .err(&format!(
+                    "Syntax error compiling at ({}:3:12).\n\
+                     Unable to resolve symbol: foo in this context\n",
+                    path.display()
+                ))
+                .ex("class clojure.lang.Compiler$CompilerException")
+                .done(),
+        )
+        .start()
+        .unwrap();
+
+    let output = unrepl(&server, &["load", path.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!(
+            "{}:3:12: Unable to resolve symbol: foo in this context\n",
+            path.display()
+        )
+    );
+
+    let requests = server.requests();
+    let load = requests
+        .iter()
+        .find(|req| unrepl::bencode::try_into_string(req["op"].clone()).unwrap() == "load-file")
+        .unwrap();
+    let arg = |name: &str| unrepl::bencode::try_into_string(load[name].clone()).unwrap();
+    assert_eq!(arg("file"), "(ns broken)\n\n(defn f [] (foo))\n");
+    assert_eq!(arg("file-path"), path.to_str().unwrap());
+    assert_eq!(arg("file-name"), "broken.clj");
+}
+
+fn dict(fields: &[(&str, BencodeValue)]) -> BencodeValue {
+    BencodeValue::Dict(
+        fields
+            .iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.clone()))
+            .collect(),
+    )
+}
+
+fn bytes(s: &str) -> BencodeValue {
+    BencodeValue::Bytes(s.as_bytes().to_vec())
+}
+
+#[test]
+fn complete_test() {
+    let completions = BencodeValue::List(vec![
+        dict(&[
+            ("candidate", bytes("map")),
+            ("type", bytes("function")),
+            ("ns", bytes("clojure.core")),
+            (
+                "arglists",
+                BencodeValue::List(vec![bytes("[f]"), bytes("[f coll]")]),
+            ),
+        ]),
+        dict(&[("candidate", bytes("mapv")), ("type", bytes("function"))]),
+    ]);
+    let reply = Reply::new().msg(vec![("completions", completions)]).done();
+
+    // cider-nrepl is preferred when both are there
+    for (ops, op) in [
+        (vec!["completions"], "completions"),
+        (vec!["completions", "complete"], "complete"),
+    ] {
+        let mut mock = MockNrepl::new();
+        for op in ops {
+            mock = mock.on(op, reply.clone());
+        }
+        let server = mock.start().unwrap();
+        let n = nrepl(&server);
+        let session = session::get_existing_session_id(&n).unwrap();
+
+        let candidates = Complete::new(session, Some("user".to_string()), "ma".to_string())
+            .send(&n)
+            .unwrap();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].candidate, "map");
+        assert_eq!(candidates[0].kind.as_deref(), Some("function"));
+        assert_eq!(candidates[0].arglists, vec!["[f]", "[f coll]"]);
+        assert!(candidates[1].ns.is_none());
+
+        let requests = server.requests();
+        let req = requests.last().unwrap();
+        assert_eq!(
+            unrepl::bencode::try_into_string(req["op"].clone()).unwrap(),
+            op
+        );
+        assert_eq!(
+            unrepl::bencode::try_into_string(req["prefix"].clone()).unwrap(),
+            "ma"
+        );
+        let extra = match op {
+            "complete" => req["extra-metadata"].clone(),
+            _ => match &req["options"] {
+                BencodeValue::Dict(options) => options[&b"extra-metadata"[..]].clone(),
+                options => panic!("expected dict, got: {:?}", options),
+            },
+        };
+        assert_eq!(extra, BencodeValue::List(vec![bytes("arglists")]));
+    }
+}
+
+#[test]
+fn eldoc_test() {
+    let cider = Reply::new()
+        .msg(vec![
+            ("name", bytes("map")),
+            ("ns", bytes("clojure.core")),
+            ("type", bytes("function")),
+            (
+                "eldoc",
+                BencodeValue::List(vec![
+                    BencodeValue::List(vec![bytes("f")]),
+                    BencodeValue::List(vec![bytes("f"), bytes("coll")]),
+                ]),
+            ),
+            ("docstring", bytes("Returns a lazy sequence")),
+        ])
+        .done();
+    let lookup = Reply::new()
+        .msg(vec![(
+            "info",
+            dict(&[
+                ("name", bytes("map")),
+                ("ns", bytes("clojure.core")),
+                ("arglists", bytes("([f] [f coll])")),
+                ("doc", bytes("Returns a lazy sequence")),
+            ]),
+        )])
+        .done();
+    let eval = Reply::new()
+        .value(
+            "{:name \"map\", :ns \"clojure.core\", :arglists \"([f] [f coll])\", \
+             :doc \"Returns a lazy sequence\", :macro nil}",
+        )
+        .done();
+
+    for (op, reply) in [("eldoc", cider), ("lookup", lookup), ("eval", eval)] {
+        let server = MockNrepl::new().on(op, reply).start().unwrap();
+        let n = nrepl(&server);
+        let session = session::get_existing_session_id(&n).unwrap();
+
+        let info = Eldoc::new(session, "user".to_string(), "map".to_string())
+            .send(&n)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.name.as_deref(), Some("map"), "{}", op);
+        assert_eq!(info.ns.as_deref(), Some("clojure.core"), "{}", op);
+        assert_eq!(info.kind, "function", "{}", op);
+        assert_eq!(info.arglists, vec!["[f]", "[f coll]"], "{}", op);
+        assert_eq!(
+            info.docstring.as_deref(),
+            Some("Returns a lazy sequence"),
+            "{}",
+            op
+        );
+    }
+
+    // Names go into evaluated code as strings, not as code
+    let server = MockNrepl::new().start().unwrap();
+    let n = nrepl(&server);
+    let session = session::get_existing_session_id(&n).unwrap();
+    Eldoc::new(
+        session,
+        "user\"".to_string(),
+        "x) (System/exit 1".to_string(),
+    )
+    .send(&n)
+    .unwrap();
+    let code = server
+        .requests()
+        .into_iter()
+        .find_map(|req| req.get("code").cloned())
+        .map(|code| unrepl::bencode::try_into_string(code).unwrap())
+        .unwrap();
+    assert!(
+        code.contains(r#"(clojure.core/symbol "user\"")"#),
+        "{}",
+        code
+    );
+    assert!(
+        code.contains(r#"(clojure.core/symbol "x) (System/exit 1")"#),
+        "{}",
+        code
+    );
+
+    let server = MockNrepl::new()
+        .on("eldoc", Reply::new().status(&["done", "no-eldoc"]))
+        .start()
+        .unwrap();
+    let n = nrepl(&server);
+    let session = session::get_existing_session_id(&n).unwrap();
+    assert!(Eldoc::new(session, "user".to_string(), "nope".to_string())
+        .send(&n)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn apropos_command_test() {
+    let matches = BencodeValue::List(vec![
+        dict(&[
+            ("name", bytes("clojure.core/map")),
+            ("type", bytes("function")),
+            (
+                "doc",
+                bytes("Returns a lazy sequence consisting of the result\n  of applying f"),
+            ),
+        ]),
+        dict(&[
+            ("name", bytes("my.app/mapper")),
+            ("type", bytes("variable")),
+        ]),
+    ]);
+    let server = MockNrepl::new()
+        .on(
+            "apropos",
+            Reply::new().msg(vec![("apropos-matches", matches)]).done(),
+        )
+        .start()
+        .unwrap();
+
+    let output = unrepl(&server, &["apropos", "map", "--docs"], "");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "clojure.core/map\tfunction\tReturns a lazy sequence consisting of the result\n\
+         my.app/mapper\tvariable\t\n"
+    );
+
+    let output = unrepl(&server, &["apropos", "map", "--json"], "");
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json[0]["type"], "function");
+    assert_eq!(json[1]["doc"], serde_json::Value::Null);
+
+    let requests = server.requests();
+    let apropos: Vec<_> = requests
+        .iter()
+        .filter(|req| unrepl::bencode::try_into_string(req["op"].clone()).unwrap() == "apropos")
+        .collect();
+    assert_eq!(apropos.len(), 2);
+    assert!(apropos[0].contains_key("docs?"));
+    assert!(!apropos[0].contains_key("privates?"));
+    assert!(!apropos[1].contains_key("docs?"));
+}
@@ -0,0 +1,112 @@
+//! Ops and commands against cider-nrepl session recorded in `fixtures/cider-session.trace`
+
+mod common;
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::Command;
+use unrepl::nrepl::ops::{Describe, GetNsName, Info, InfoResponseType};
+use unrepl::nrepl::replay::{self, Fixture};
+use unrepl::nrepl::{session, NreplOp, NreplStream};
+
+const NS_FILE: &str = "/home/dev/app/src/my/app/core.clj";
+
+fn replay_server() -> SocketAddr {
+    common::setup();
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/cider-session.trace");
+
+    replay::spawn(Fixture::load(&path).unwrap()).unwrap()
+}
+
+fn nrepl() -> NreplStream {
+    NreplStream::persistent(&replay_server().into()).unwrap()
+}
+
+#[test]
+fn describe_test() {
+    let ops = Describe::new(false).send(&nrepl()).unwrap().into_ops();
+
+    assert!(ops.contains("info"));
+    assert!(ops.contains("ls-sessions"));
+    assert!(!ops.contains("unknown"));
+}
+
+#[test]
+fn existing_session_is_reused_test() {
+    let n = nrepl();
+
+    let created = session::get_existing_session_id(&n).unwrap();
+    assert_eq!(created.id(), "5e0b2b6e-8e29-4c3b-a0c4-2f8a1d6c9f31");
+    assert!(created.is_op_available("info"));
+
+    // Found in the database and validated with `ls-sessions`
+    let existing = session::get_existing_session_id(&n).unwrap();
+    assert_eq!(existing.id(), created.id());
+}
+
+#[test]
+fn get_ns_name_test() {
+    let n = nrepl();
+    let session = session::get_existing_session_id(&n).unwrap();
+
+    let ns = GetNsName::new(NS_FILE.to_string(), session)
+        .send(&n)
+        .unwrap();
+    assert_eq!(ns, Some("my.app.core".to_string()));
+}
+
+#[test]
+fn info_test() {
+    let n = nrepl();
+    let session = session::get_existing_session_id(&n).unwrap();
+    let info = |symbol: &str| {
+        Info::new(
+            session.clone(),
+            "my.app.core".to_string(),
+            symbol.to_string(),
+        )
+        .send(&n)
+        .unwrap()
+    };
+
+    match info("map") {
+        Some(InfoResponseType::Symbol(resp)) => {
+            assert_eq!(resp.line, 2727);
+            assert_eq!(resp.col, Some(1));
+            assert_eq!(resp.resource, "clojure/core.clj");
+            assert!(resp
+                .doc
+                .starts_with("clojure.core/map\n([f])\n([f coll])\n([f c1 c2])"));
+        }
+        _ => panic!("expected symbol info"),
+    }
+
+    match info("clojure.string") {
+        Some(InfoResponseType::Ns(resp)) => {
+            assert_eq!(resp.line, 9);
+            assert!(resp
+                .doc
+                .starts_with("clojure.string\nClojure String utilities"));
+        }
+        _ => panic!("expected namespace info"),
+    }
+
+    assert!(info("no-such-thing").is_none());
+}
+
+#[test]
+fn doc_command_test() {
+    let addr = replay_server();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_unrepl"))
+        .env("XDG_DATA_HOME", common::data_dir())
+        .args(["-p", &addr.to_string(), "doc", NS_FILE, "map"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("clojure.core/map\n([f])"));
+}